@@ -0,0 +1,31 @@
+//! Benchmarks of the encoding and decoding hot paths, run with `cargo bench`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::hint::black_box;
+use zeiss_inspect_api_rust::test_data::{self, KeyOrder};
+use zeiss_inspect_api_rust::{decode, Codec};
+
+fn codec(c: &mut Criterion) {
+    let codec = Codec::new();
+
+    let list = test_data::integer_list(10_000);
+    let encoded_list = codec.encode(&list);
+    c.bench_function("encode integer list 10k", |b| b.iter(|| codec.encode(black_box(&list))));
+    c.bench_function("decode integer list 10k", |b| b.iter(|| decode(black_box(&encoded_list)).unwrap()));
+
+    for (name, order) in [("sorted", KeyOrder::Sorted), ("shuffled", KeyOrder::Shuffled)] {
+        let map = test_data::string_map(1_000, order);
+        c.bench_function(&format!("encode string map 1k {}", name), |b| b.iter(|| codec.encode(black_box(&map))));
+        #[cfg(feature = "ordered-maps")]
+        {
+            let map = test_data::ordered_string_map(1_000, order);
+            c.bench_function(&format!("encode ordered string map 1k {}", name), |b| b.iter(|| codec.encode(black_box(&map))));
+        }
+    }
+
+    let blob = test_data::blob(5 * 1024 * 1024);
+    c.bench_function("round trip blob 5MB", |b| b.iter(|| decode(&codec.encode(black_box(&blob))).unwrap()));
+}
+
+criterion_group!(benches, codec);
+criterion_main!(benches);
@@ -0,0 +1,134 @@
+//! Benchmarks of building the item map sent with every item request, run with `cargo bench --bench item_map`.
+//!
+//! Besides the criterion timings, the number of heap allocations for a 10k-item pass is printed
+//! for a freshly built map per item and for one map reused with `Item::write_to_map`. The same is
+//! printed for item requests on the 10k items of a `filter` result, sent through a `Connection` to
+//! a local server, once with a fresh map per request and once with the maps the connection reuses.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::hint::black_box;
+use std::sync::{Arc, Mutex};
+use zeiss_inspect_api_rust::{CdcDict, CdcEncoder, CdcValue, CompareParams, Connection, ConnectionHandle, GetParams, Item, Request};
+
+struct CountingAllocator;
+
+thread_local! {
+    /// Allocations of the current thread, so the server thread of the request benchmark isn't counted
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Returns the number of allocations `f` performs on the current thread
+fn allocations<R>(f: impl FnOnce() -> R) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    black_box(f());
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+/// Number of items of the filter result
+const FILTER_ITEMS: usize = 10_000;
+
+/// Starts a server that answers `FILTER` with `FILTER_ITEMS` items, `LESS` with false and
+/// everything else with NONE, returning its URL
+fn filter_server() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut socket = tungstenite::accept(stream).unwrap();
+        let mut encoder = CdcEncoder::new();
+        let items = CdcValue::LIST((0..FILTER_ITEMS).map(|i| CdcValue::ITEM(Item::new(format!("element_{:05}", i), 1, -1))).collect());
+        while let Ok(msg) = socket.read() {
+            let mut request = encoder.decode_value(&mut msg.into_data().as_ref()).unwrap().expect_map();
+            let value = match request.remove("value").unwrap().expect_int() {
+                command if command == Request::FILTER as i64 => items.clone(),
+                command if command == Request::LESS as i64 => CdcValue::BOOL(false),
+                _ => CdcValue::NONE,
+            };
+            let reply = CdcDict::from([
+                ("type".to_string(), CdcValue::STRING("reply".to_string())),
+                ("id".to_string(), request.remove("id").unwrap()),
+                ("value".to_string(), value),
+            ]);
+            if socket.send(tungstenite::Message::Binary(encoder.encode(CdcValue::MAP(reply)).into())).is_err() {
+                return;
+            }
+        }
+    });
+    url
+}
+
+/// Prints the allocations of item requests on a 10k-item filter result
+fn item_requests() {
+    let connection = Arc::new(Mutex::new(Connection::init(&filter_server(), String::new()).unwrap()));
+    let handle = ConnectionHandle::Explicit(connection.clone());
+    let root = Item::new("root".to_string(), 0, -1);
+    let items = handle.enter(|| root.filter_items("element", None)).unwrap();
+    assert_eq!(items.len(), FILTER_ITEMS);
+
+    // A fresh map per request, like before the connection reused them
+    let fresh_get = || {
+        let mut conn = connection.lock().unwrap();
+        for item in &items {
+            conn.request(Request::GET, GetParams { item: item.to_json(), name: "name", index: None }).unwrap();
+        }
+    };
+    let fresh_less = || {
+        let mut conn = connection.lock().unwrap();
+        for pair in items.windows(2) {
+            conn.request(Request::LESS, CompareParams { item: pair[0].to_json(), other: pair[1].to_json() }).unwrap();
+        }
+    };
+    let reused_get = || handle.enter(|| items.iter().for_each(|item| { black_box(item.get("name", None).unwrap()); }));
+    let reused_less = || handle.enter(|| items.windows(2).for_each(|pair| { black_box(pair[0].less_than(&pair[1]).unwrap()); }));
+
+    // Warm up the connection so only the steady state is counted
+    reused_less();
+    println!("get request 10k fresh: {} allocations", allocations(fresh_get));
+    println!("get request 10k reused: {} allocations", allocations(reused_get));
+    println!("less request 10k fresh: {} allocations", allocations(fresh_less));
+    println!("less request 10k reused: {} allocations", allocations(reused_less));
+}
+
+fn item_map(c: &mut Criterion) {
+    let items: Vec<Item> = (0..10_000).map(|i| Item::new(format!("element_{:05}", i), 1, -1)).collect();
+
+    let fresh = || {
+        for item in &items {
+            black_box(item.to_json());
+        }
+    };
+    let mut map = CdcDict::new();
+    let mut reused = || {
+        for item in &items {
+            item.write_to_map(&mut map);
+            black_box(&map);
+        }
+    };
+
+    // Warm up the reused map so only the steady state is counted
+    reused();
+    println!("item map 10k fresh: {} allocations", allocations(fresh));
+    println!("item map 10k reused: {} allocations", allocations(&mut reused));
+    item_requests();
+
+    c.bench_function("item map 10k fresh", |b| b.iter(fresh));
+    c.bench_function("item map 10k reused", |b| b.iter(&mut reused));
+}
+
+criterion_group!(benches, item_map);
+criterion_main!(benches);
@@ -0,0 +1,94 @@
+//! Checks that a GOM server build is wire compatible with this client.
+//!
+//! `run` sends a sample of every transferable `CdcType` in the `value` parameter of a
+//! `TEST` request and expects the server to echo it back unchanged.
+
+use crate::encoding::{CdcDict, CdcType, CdcValue};
+use crate::network::{Connection, ConnectionError, Request};
+use crate::params::keys;
+use crate::{Array, CdcError, Indexable, Item, Object, Package, Slice, Trait, Vec2d, Vec3d};
+
+/// Why a sample didn't survive the round trip
+#[derive(Debug)]
+pub enum Failure {
+    /// The `TEST` request failed
+    Request(ConnectionError),
+    /// The server echoed a different value than the sample of the type
+    Mismatch { received: CdcValue },
+}
+
+/// Returns one sample value per type that can be sent to the server.
+///
+/// `COMMAND` and `CALLABLE` are left out, they only refer to state of the local encoder.
+pub fn samples() -> Vec<CdcValue> {
+    let item = Item::new("conformance".to_string(), 42, 1);
+    vec![
+        CdcValue::NONE,
+        CdcValue::BOOL(true),
+        CdcValue::INTEGER(-1234567890123),
+        CdcValue::FLOAT(0.1),
+        CdcValue::STRING("Zeiss \u{00e4}\u{00f6}\u{00fc}".to_string()),
+        CdcValue::LIST(vec![CdcValue::INTEGER(1), CdcValue::STRING("two".to_string()), CdcValue::NONE]),
+        CdcValue::MAP(CdcDict::from([("key".to_string(), CdcValue::FLOAT(2.5))])),
+        CdcValue::SLICE(Slice { start: Some(1), stop: None }),
+        CdcValue::ITEM(item.clone()),
+        CdcValue::INDEXABLE(Indexable { item: item.clone(), token: "token".to_string(), size: 3 }),
+        CdcValue::ERROR(CdcError { id: "error".to_string(), text: "text".to_string(), line: 7 }),
+        Trait { id: "trait".to_string(), args: vec![CdcValue::INTEGER(1)], kwargs: CdcDict::new() }.into(),
+        Object::new("type".to_string(), "repr".to_string()).into(),
+        Array {
+            project: Box::new(CdcValue::NONE),
+            item: Box::new(CdcValue::ITEM(item)),
+            key: "data.coordinate".to_string(),
+            index: vec![0, 1],
+            selected: false,
+            transformation: None,
+        }
+        .into(),
+        Package::new("package".to_string()).into(),
+        CdcValue::VEC2D(Vec2d { x: 1.5, y: -2.5 }),
+        CdcValue::VEC3D(Vec3d { x: 1.0, y: 2.0, z: 3.0 }),
+        CdcValue::RESOURCE_ACCESS,
+        CdcValue::BLOB(vec![0, 1, 254, 255]),
+    ]
+}
+
+/// Round-trips every sample of `samples` through the server, returning the result per type
+pub fn run(conn: &mut Connection) -> Vec<(CdcType, Result<(), Failure>)> {
+    samples().into_iter().map(|sent| (CdcType::from(&sent), round_trip(conn, sent))).collect()
+}
+
+fn round_trip(conn: &mut Connection, sent: CdcValue) -> Result<(), Failure> {
+    let params = CdcDict::from([(keys::VALUE.to_string(), sent.clone())]);
+    let received = match conn.request(Request::TEST, params) {
+        Ok(value) => value,
+        // Errors in a reply are raised, so an echoed error arrives as one
+        Err(ConnectionError::CdcError(err)) => CdcValue::ERROR(err),
+        Err(err) => return Err(Failure::Request(err)),
+    };
+    if received == sent {
+        Ok(())
+    } else {
+        Err(Failure::Mismatch { received })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::test_server;
+
+    #[test]
+    fn test_conformance() {
+        let url = test_server::replying(1, |command, params| match command {
+            command if command == Request::TEST as i64 => Ok(params[keys::VALUE].clone()),
+            _ => Err("RuntimeError"),
+        });
+        let mut conn = Connection::init(&url, String::new()).unwrap();
+        let results = run(&mut conn);
+        assert_eq!(results.len(), samples().len());
+        for (cdc_type, result) in results {
+            assert!(result.is_ok(), "{:?} failed: {:?}", cdc_type, result);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use crate::encoding::{CdcDict, CdcValue, DEFAULT_MAX_DEPTH};
+use serde_json::{Map, Value};
+
+/// How `CdcValue::BLOB` values are represented in JSON
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlobEncoding {
+    /// Standard base64 string with padding
+    #[default]
+    Base64,
+    /// Lowercase hexadecimal string
+    Hex,
+    /// Array of byte values
+    Array,
+}
+
+/// Controls the conversion of values into JSON, see `CdcValue::to_json`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonConfig {
+    /// Represent `NONE` as `null`. If false, map entries holding `NONE` are omitted;
+    /// `NONE` inside lists and at the top level is still `null` as there is nothing to omit.
+    pub none_as_null: bool,
+    /// Representation of blobs
+    pub blob_encoding: BlobEncoding,
+}
+
+impl Default for JsonConfig {
+    fn default() -> Self {
+        JsonConfig {
+            none_as_null: true,
+            blob_encoding: BlobEncoding::Base64,
+        }
+    }
+}
+
+impl CdcValue {
+    /// Converts this value into JSON.
+    ///
+    /// Values without a JSON equivalent (callables, resource access, non-finite floats)
+    /// are treated like `NONE`, as are values nested deeper than `DEFAULT_MAX_DEPTH`.
+    pub fn to_json(&self, config: &JsonConfig) -> Value {
+        self.to_json_nested(config, 1)
+    }
+
+    fn to_json_nested(&self, config: &JsonConfig, depth: usize) -> Value {
+        self.to_json_opt(config, depth).unwrap_or(Value::Null)
+    }
+
+    /// Converts this value, returning None for values that are omitted from maps
+    fn to_json_opt(&self, config: &JsonConfig, depth: usize) -> Option<Value> {
+        let value = match self {
+            _ if depth > DEFAULT_MAX_DEPTH => Value::Null,
+            CdcValue::NONE | CdcValue::CALLABLE(_) | CdcValue::RESOURCE_ACCESS => Value::Null,
+            CdcValue::BOOL(b) => Value::Bool(*b),
+            CdcValue::INTEGER(i) => Value::from(*i),
+            CdcValue::FLOAT(f) => serde_json::Number::from_f64(*f).map_or(Value::Null, Value::Number),
+            CdcValue::STRING(s) => Value::String(s.clone()),
+            CdcValue::LIST(list) => Value::Array(list.iter().map(|value| value.to_json_nested(config, depth + 1)).collect()),
+            CdcValue::MAP(map) => map_to_json(map.iter(), config, depth),
+            #[cfg(feature = "ordered-maps")]
+            CdcValue::ORDERED_MAP(map) => map_to_json(map.iter(), config, depth),
+            CdcValue::BLOB(data) => match config.blob_encoding {
+                BlobEncoding::Base64 => Value::String(base64(data)),
+                BlobEncoding::Hex => Value::String(data.iter().map(|byte| format!("{:02x}", byte)).collect()),
+                BlobEncoding::Array => Value::Array(data.iter().map(|byte| Value::from(*byte)).collect()),
+            },
+            CdcValue::VEC2D(v) => Value::from(vec![v.x, v.y]),
+            CdcValue::VEC3D(v) => Value::from(vec![v.x, v.y, v.z]),
+            CdcValue::COMMAND(cmd) => Value::String(cmd.name.clone()),
+            CdcValue::SLICE(slice) => object([
+                ("start", slice.start.map_or(CdcValue::NONE, CdcValue::INTEGER)),
+                ("stop", slice.stop.map_or(CdcValue::NONE, CdcValue::INTEGER)),
+            ], config, depth),
+            CdcValue::ITEM(item) => map_to_json(item.to_json().iter(), config, depth),
+            CdcValue::INDEXABLE(indexable) => object([
+                ("item", CdcValue::ITEM(indexable.item.clone())),
+                ("token", CdcValue::STRING(indexable.token.clone())),
+                ("size", CdcValue::INTEGER(indexable.size)),
+            ], config, depth),
+            CdcValue::ERROR(error) => object([
+                ("id", CdcValue::STRING(error.id.clone())),
+                ("text", CdcValue::STRING(error.text.clone())),
+                ("line", CdcValue::INTEGER(error.line)),
+            ], config, depth),
+            CdcValue::TRAIT(trait_obj) => object([
+                ("id", CdcValue::STRING(trait_obj.id.clone())),
+                ("args", CdcValue::LIST(trait_obj.args.clone())),
+                ("kwargs", CdcValue::MAP(trait_obj.kwargs.clone())),
+            ], config, depth),
+            CdcValue::OBJECT(obj) => object([
+                ("type_id", CdcValue::STRING(obj.type_id.clone())),
+                ("repr", CdcValue::STRING(obj.repr.clone())),
+                ("attributes", CdcValue::MAP(obj.attributes.clone())),
+            ], config, depth),
+            CdcValue::ARRAY(arr) => object([
+                ("project", (*arr.project).clone()),
+                ("item", (*arr.item).clone()),
+                ("key", CdcValue::STRING(arr.key.clone())),
+                ("index", CdcValue::LIST(arr.index.iter().map(|i| CdcValue::INTEGER(*i)).collect())),
+                ("selected", CdcValue::BOOL(arr.selected)),
+                ("transformation", arr.transformation.as_deref().cloned().unwrap_or(CdcValue::NONE)),
+            ], config, depth),
+            CdcValue::PACKAGE(pkg) => object([
+                ("reference", CdcValue::STRING(pkg.reference.clone())),
+                ("metadata", CdcValue::MAP(pkg.metadata.clone())),
+            ], config, depth),
+        };
+        if value.is_null() && !config.none_as_null {
+            None
+        } else {
+            Some(value)
+        }
+    }
+}
+
+/// Converts the entries of a map at `depth`, its values are one level deeper
+fn map_to_json<'a>(entries: impl Iterator<Item = (&'a String, &'a CdcValue)>, config: &JsonConfig, depth: usize) -> Value {
+    let mut map = Map::new();
+    for (key, value) in entries {
+        if let Some(value) = value.to_json_opt(config, depth + 1) {
+            map.insert(key.clone(), value);
+        }
+    }
+    Value::Object(map)
+}
+
+fn object<const N: usize>(fields: [(&str, CdcValue); N], config: &JsonConfig, depth: usize) -> Value {
+    let fields: CdcDict = fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect();
+    map_to_json(fields.iter(), config, depth)
+}
+
+/// Encodes bytes as standard base64 with padding
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_none_representation() {
+        let mut map = CdcDict::new();
+        map.insert("none".to_string(), CdcValue::NONE);
+        map.insert("list".to_string(), CdcValue::LIST(vec![CdcValue::NONE, CdcValue::INTEGER(1)]));
+        let value = CdcValue::MAP(map);
+
+        assert_eq!(value.to_json(&JsonConfig::default()), json!({"none": null, "list": [null, 1]}));
+        let omit = JsonConfig { none_as_null: false, ..JsonConfig::default() };
+        assert_eq!(value.to_json(&omit), json!({"list": [null, 1]}));
+    }
+
+    #[test]
+    fn test_blob_encodings() {
+        let blob = CdcValue::BLOB(b"Man is".to_vec());
+        assert_eq!(blob.to_json(&JsonConfig::default()), json!("TWFuIGlz"));
+        assert_eq!(CdcValue::BLOB(b"Ma".to_vec()).to_json(&JsonConfig::default()), json!("TWE="));
+        assert_eq!(CdcValue::BLOB(b"M".to_vec()).to_json(&JsonConfig::default()), json!("TQ=="));
+
+        let hex = JsonConfig { blob_encoding: BlobEncoding::Hex, ..JsonConfig::default() };
+        assert_eq!(CdcValue::BLOB(vec![0x01, 0xab]).to_json(&hex), json!("01ab"));
+        let array = JsonConfig { blob_encoding: BlobEncoding::Array, ..JsonConfig::default() };
+        assert_eq!(CdcValue::BLOB(vec![1, 2]).to_json(&array), json!([1, 2]));
+    }
+}
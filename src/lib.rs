@@ -1,30 +1,82 @@
 // This file contains the main Rust library code. It includes a simple "Hello World" function that can be called from Python.
+//
+// Without the `std` feature only the codec and the payload types are built, on `core` and `alloc`.
+#![cfg_attr(not(feature = "std"), no_std)]
 
+extern crate alloc;
 
+#[cfg(not(any(feature = "std", feature = "alloc")))]
+compile_error!("Enable the `std` feature, or the `alloc` feature for a no_std build of the codec");
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
 use std::collections::HashMap;
+#[cfg(not(feature = "std"))]
+use hashbrown::HashMap;
+#[cfg(feature = "std")]
 use std::cell::RefCell;
+#[cfg(feature = "std")]
+use std::sync::atomic::AtomicBool;
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex, PoisonError};
+#[cfg(feature = "std")]
+use std::time::Duration;
 
+#[cfg(feature = "std")]
+pub mod conformance;
+pub mod test_data;
 mod encoding;
+#[cfg(feature = "json")]
+mod json;
+#[cfg(feature = "std")]
 mod network;
+#[cfg(feature = "std")]
+mod params;
+#[cfg(feature = "std")]
 mod types;
 
-use encoding::{CdcValue, CdcList, CdcDict};
-use network::{Connection};
-use uuid;
+pub use encoding::{CdcValue, CdcList, CdcDict, CdcType, CdcBuilder, CdcListBuilder, CdcMapBuilder, DiffEntry, DiffKind, Codec, decode, decode_all, decode_complete, decode_one, CdcEncoder, CdcCallable, CallableHandle, CdcRead, EncoderStats, DecodeError, EncodeError, EncodeLimits, NonFinitePolicy, DEFAULT_MAX_DEPTH};
+#[cfg(feature = "ordered-maps")]
+pub use encoding::CdcOrderedDict;
+#[cfg(feature = "json")]
+pub use json::{BlobEncoding, JsonConfig};
+#[cfg(feature = "std")]
+pub use params::{insert_optional, ItemParams, GetParams, IndexParams, FilterParams, CompareParams, GetAttrParams, SetAttrParams, KeyParams, DocParams, CommandParams, TranslateParams, QueryParams, LineParams, RunApiParams, ConfigurationParams, ReleaseParams, RegisterParams};
+#[cfg(feature = "std")]
+pub use network::{Capabilities, Connection, ConnectionError, Envelope, EnvelopeError, EnvelopeKind, ServerError, LogConfig, Request, SocketConfig, TestCase, ToParams, Transport, UnexpectedReply, DEFAULT_MAX_BUFFERED_REPLIES};
+#[cfg(feature = "tls")]
+pub use network::TlsConfig;
+#[cfg(feature = "async")]
+pub use network::{AsyncConnection, RequestPriority};
 
+#[cfg(feature = "std")]
 use std::env;
 
 // Re-export types module functions publicly
+#[cfg(feature = "std")]
 pub use types::{register_type, is_type_registered, get_type_name, get_all_registered_types, clear_type_cache, clear_all_caches};
 
+#[cfg(feature = "std")]
 thread_local! {
     static GOM_CONNECTION: RefCell<Option<Connection>> = RefCell::new(None);
+    /// The connection `Global` handles resolve to while [`ConnectionHandle::enter`] runs
+    static ENTERED_CONNECTION: RefCell<Option<SharedConnection>> = const { RefCell::new(None) };
+}
+
+/// Installs `conn` as the GOM connection of the current thread
+#[cfg(feature = "std")]
+fn install_gom_connection(conn: Connection) {
+    GOM_CONNECTION.with(|conn_cell| *conn_cell.borrow_mut() = Some(conn));
 }
 
+#[cfg(feature = "std")]
 fn get_api_url() -> Option<String> {
     env::var("TOM_PYTHON_API_URL").ok()
 }
 
+#[cfg(feature = "std")]
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
 struct ConnectionConfig {
@@ -32,66 +84,148 @@ struct ConnectionConfig {
     api_key: String,
     interpreter_id: String,
     strip_tracebacks: bool,
+    /// Time allowed for connecting and for the initial register round trip each
+    timeout: Duration,
+}
+
+/// Default of the connect and register timeouts of [`initialize_gom_connection`]
+#[cfg(feature = "std")]
+pub const DEFAULT_INIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Options of [`initialize_gom_connection_with`] and [`GomSession::connect_with`]
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    /// Time allowed for connecting and for the initial register round trip each
+    pub timeout: Duration,
+    /// The id to register the interpreter with instead of the `interpreter_id` of the URL.
+    ///
+    /// A stable id, e.g. the name of the application, makes the interpreter easy to find in the
+    /// interpreter list of ZEISS Inspect.
+    pub interpreter_id: Option<String>,
+}
+
+#[cfg(feature = "std")]
+impl Default for ConnectOptions {
+    /// The default timeout and the interpreter id of the URL
+    fn default() -> Self {
+        ConnectOptions { timeout: DEFAULT_INIT_TIMEOUT, interpreter_id: None }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for ConnectionConfig {
+    /// No server and API key, a fresh interpreter id, stripped tracebacks and the default timeout
+    fn default() -> Self {
+        ConnectionConfig {
+            server_url: String::new(),
+            api_key: String::new(),
+            interpreter_id: uuid::Uuid::new_v4().to_string(),
+            strip_tracebacks: true,
+            timeout: DEFAULT_INIT_TIMEOUT,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl ConnectionConfig {
+    /// Applies `options` on top of the configuration parsed from the URL
+    fn with_options(self, options: ConnectOptions) -> Self {
+        ConnectionConfig {
+            timeout: options.timeout,
+            interpreter_id: options.interpreter_id.unwrap_or(self.interpreter_id),
+            ..self
+        }
+    }
 }
 
+#[cfg(feature = "std")]
 fn parse_connection_config(api_url: &str) -> Result<ConnectionConfig, Box<dyn std::error::Error>> {
     let server_url = api_url.to_string();
     
     // Parse query parameters manually
     let query_start = api_url.find('?');
-    let mut api_key = String::new();
-    let mut interpreter_id = uuid::Uuid::new_v4().to_string();
-    let mut strip_tracebacks = true;
+    let ConnectionConfig { mut api_key, mut interpreter_id, mut strip_tracebacks, .. } = ConnectionConfig::default();
     
     if let Some(query_start) = query_start {
         let query = &api_url[query_start + 1..];
-        for pair in query.split('&') {
-            let mut parts = pair.split('=');
-            if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
-                match key {
-                    "apikey" => api_key = value.to_string(),
-                    "interpreter_id" => interpreter_id = value.to_string(),
-                    "strip_tracebacks" => strip_tracebacks = value == "1",
-                    _ => {}
-                }
+        // Empty segments, e.g. from a trailing `?` or `&&`, carry nothing and are skipped
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let Some((key, value)) = pair.split_once('=') else {
+                return Err(format!("Malformed query parameter '{}' in the API URL, expected key=value", pair).into());
+            };
+            match key {
+                "apikey" => api_key = value.to_string(),
+                "interpreter_id" => interpreter_id = value.to_string(),
+                "strip_tracebacks" => strip_tracebacks = value == "1",
+                _ => log::debug!("Ignoring unknown query parameter '{}' of the API URL", key),
             }
         }
     }
+    if api_key.is_empty() {
+        log::warn!("The API URL has no API key, the server will likely reject requests");
+    }
     
     Ok(ConnectionConfig {
         server_url,
         api_key,
         interpreter_id,
         strip_tracebacks,
+        ..ConnectionConfig::default()
     })
 }
 
+/// The current executable path, used as the file identifier when registering the interpreter
+#[cfg(feature = "std")]
+fn script_file_path() -> String {
+    std::env::current_exe()
+        .ok()
+        .and_then(|path| {
+            path.to_str()
+                .map(|s| s.replace("\\", "/"))
+        })
+        .unwrap_or_else(|| "zeiss_inspect_api_rust".to_string())
+}
+
+/// Connects to the ZEISS Inspect instance given by `TOM_PYTHON_API_URL`, if set.
+///
+/// Connecting and registering the interpreter may take up to [`DEFAULT_INIT_TIMEOUT`] each;
+/// failures, including timeouts, are logged.
+#[cfg(feature = "std")]
 pub fn initialize_gom_connection() {
+    initialize_gom_connection_timeout(DEFAULT_INIT_TIMEOUT);
+}
+
+/// Connects like [`initialize_gom_connection`], with a custom connect and register timeout.
+///
+/// # Arguments
+/// * `timeout` - The time allowed for connecting and for registering the interpreter each
+#[cfg(feature = "std")]
+pub fn initialize_gom_connection_timeout(timeout: Duration) {
+    initialize_gom_connection_with(ConnectOptions { timeout, ..ConnectOptions::default() });
+}
+
+/// Connects like [`initialize_gom_connection`], with custom options, e.g. the interpreter id.
+///
+/// # Arguments
+/// * `options` - The timeout and interpreter id to connect with
+#[cfg(feature = "std")]
+pub fn initialize_gom_connection_with(options: ConnectOptions) {
     if let Some(api_url) = get_api_url() {
         match parse_connection_config(&api_url) {
             Ok(config) => {
-                match Connection::init(&config.server_url, config.api_key) {
+                let config = config.with_options(options);
+                match Connection::init_timeout(&config.server_url, config.api_key, config.timeout) {
                     Ok(mut conn) => {
-                        // Get the current executable path to use as the file identifier
-                        let file_path = std::env::current_exe()
-                            .ok()
-                            .and_then(|path| {
-                                path.to_str()
-                                    .map(|s| s.replace("\\", "/"))
-                            })
-                            .unwrap_or_else(|| "zeiss_inspect_api_rust".to_string());
-                        
-                        match conn.register(&config.interpreter_id, &file_path) {
+                        match conn.register_timeout(&config.interpreter_id, &script_file_path(), config.timeout) {
                             Ok(_) => {
-                                GOM_CONNECTION.with(|conn_cell| {
-                                    *conn_cell.borrow_mut() = Some(conn);
-                                });
+                                install_gom_connection(conn);
                                 log::info!("GOM connection initialized successfully");
                             }
                             Err(e) => log::error!("Failed to register interpreter: {:?}", e),
                         }
                     }
-                    Err(e) => log::error!("Failed to initialize connection: {:?}", e),
+                    Err(e) => log::error!("Failed to initialize connection: {}", e),
                 }
             }
             Err(e) => log::error!("Failed to parse connection config: {:?}", e),
@@ -101,6 +235,138 @@ pub fn initialize_gom_connection() {
     }
 }
 
+/// Releases the interpreter and closes the GOM connection of the current thread.
+///
+/// Afterwards the thread is disconnected like before [`initialize_gom_connection`], which may be
+/// called again. Does nothing if the thread has no connection; failures to release are logged.
+#[cfg(feature = "std")]
+pub fn shutdown_gom_connection() {
+    let Some(conn) = GOM_CONNECTION.with(|conn_cell| conn_cell.borrow_mut().take()) else {
+        return;
+    };
+    match conn.close() {
+        Ok(()) => log::info!("GOM connection shut down"),
+        Err(e) => log::warn!("Failed to release the interpreter: {:?}", e),
+    }
+}
+
+/// A connection that can be shared between threads, e.g. by the workers of a service
+#[cfg(feature = "std")]
+pub type SharedConnection = Arc<Mutex<Connection>>;
+
+/// The connection a call is made on.
+///
+/// Scripts started by ZEISS Inspect use the connection of their thread, while services often pass
+/// a connection explicitly. Functions taking a handle end in `_in`, e.g. [`execute_command_in`],
+/// [`tr_in`] and [`resolve_item_in`]; all other free functions and the [`Item`] methods use a
+/// `Global` handle, which [`ConnectionHandle::enter`] redirects. A handle is resolved as follows:
+/// 1. `Explicit` uses the given connection, even if the current thread has one, waiting until no
+///    other thread uses it
+/// 2. `Global` inside [`ConnectionHandle::enter`] uses the entered connection like `Explicit`
+/// 3. `Global` otherwise uses the connection of the current thread, set up by
+///    [`initialize_gom_connection`] or [`GomSession::install`], and fails with `ConnectionError::NotConnected`
+///    if there is none
+#[cfg(feature = "std")]
+#[derive(Clone, Default)]
+pub enum ConnectionHandle {
+    #[default]
+    Global,
+    Explicit(SharedConnection),
+}
+
+#[cfg(feature = "std")]
+impl ConnectionHandle {
+    /// Runs `f` with the connection the handle resolves to
+    pub fn with<R>(&self, f: impl FnOnce(&mut Connection) -> Result<R, network::ConnectionError>) -> Result<R, network::ConnectionError> {
+        match self {
+            ConnectionHandle::Global => match ENTERED_CONNECTION.with(|entered| entered.borrow().clone()) {
+                Some(conn) => ConnectionHandle::Explicit(conn).with(f),
+                None => GOM_CONNECTION.with(|conn_cell| match conn_cell.borrow_mut().as_mut() {
+                    Some(conn) => f(conn),
+                    None => Err(network::ConnectionError::NotConnected),
+                }),
+            },
+            // A panic while the connection was locked doesn't leave it in a state worse than a failed request
+            ConnectionHandle::Explicit(conn) => f(&mut conn.lock().unwrap_or_else(PoisonError::into_inner)),
+        }
+    }
+
+    /// Runs `f` with `Global` handles resolving to this handle, so the free functions and [`Item`]
+    /// methods called by `f` use its connection, e.g. `handle.enter(|| item.get_attr("name"))`.
+    ///
+    /// Entering a `Global` handle keeps the current resolution. The previous resolution is restored
+    /// when `f` returns or panics.
+    pub fn enter<R>(&self, f: impl FnOnce() -> R) -> R {
+        let ConnectionHandle::Explicit(conn) = self else {
+            return f();
+        };
+        struct Restore(Option<SharedConnection>);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                ENTERED_CONNECTION.with(|entered| *entered.borrow_mut() = self.0.take());
+            }
+        }
+        let _restore = Restore(ENTERED_CONNECTION.with(|entered| entered.replace(Some(conn.clone()))));
+        f()
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::fmt::Debug for ConnectionHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionHandle::Global => write!(f, "Global"),
+            ConnectionHandle::Explicit(conn) => write!(f, "Explicit({:p})", Arc::as_ptr(conn)),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl PartialEq for ConnectionHandle {
+    /// Explicit handles are equal if they share the same connection
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (ConnectionHandle::Global, ConnectionHandle::Global) => true,
+            (ConnectionHandle::Explicit(a), ConnectionHandle::Explicit(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+/// Stage and selection a command is executed against, and how its result is awaited.
+///
+/// The default context omits stage and selection, leaving them to the server's current state,
+/// and waits for the result indefinitely.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExecutionContext {
+    /// Stage to execute the command in, [`Stage::Current`] resolving to the connection's default stage
+    pub stage: Option<Stage>,
+    /// Items the command operates on instead of the current selection
+    pub selection: Vec<Item>,
+    /// Time to wait for the result before failing with `ConnectionError::Timeout`, `None` waits indefinitely
+    pub timeout: Option<Duration>,
+    /// Key that makes retries of the command safe: executing it again with the same key after a
+    /// timeout waits for the original execution instead of starting another one, see
+    /// [`Connection::request_idempotent`]
+    pub idempotency_key: Option<String>,
+    /// Connection to execute the command on, the connection of the current thread by default
+    pub connection: ConnectionHandle,
+}
+
+#[cfg(feature = "std")]
+impl ExecutionContext {
+    /// Adds the set fields of the context to the parameters of a command request
+    fn write_params(&self, conn: &mut network::Connection, params: &mut params::CommandParams) {
+        params.stage = self.stage.map(|stage| i64::from(conn.resolve_stage(stage)));
+        if !self.selection.is_empty() {
+            // Sent as item maps like the items of all other requests
+            let selection = self.selection.iter().map(|item| CdcValue::MAP(conn.item_map(item))).collect();
+            params.selection = Some(selection);
+        }
+    }
+}
+
 /// Executes a GOM command with positional and keyword arguments.
 ///
 /// This function executes a command in the GOM application, passing both positional arguments
@@ -113,23 +379,149 @@ pub fn initialize_gom_connection() {
 ///
 /// # Returns
 /// The result of the command execution, or an error if the command fails
+#[cfg(feature = "std")]
 pub fn execute_command(command_name: &str, args: CdcList, kwargs: CdcDict) -> Result<CdcValue, network::ConnectionError> {
-    GOM_CONNECTION.with(|conn_cell| {
-        let mut conn_guard = conn_cell.borrow_mut();
-        
-        if let Some(conn) = conn_guard.as_mut() {
-            let mut params = HashMap::new();
-            params.insert("command".to_string(), CdcValue::STRING(command_name.to_string()));
-            params.insert("args".to_string(), CdcValue::LIST(args));
-            params.insert("kwargs".to_string(), CdcValue::MAP(kwargs));
-            
-            conn.request(network::Request::COMMAND, params)
-        } else {
-            Err(network::ConnectionError::Request)
+    execute_command_in(command_name, args, kwargs, &ExecutionContext::default())
+}
+
+/// Executes a GOM command whose result is a list of items, e.g. created or imported elements.
+///
+/// Fails with `ConnectionError::UnexpectedReply` if the result is not a list or contains anything
+/// other than items or item parameter maps.
+///
+/// # Arguments
+/// * `command_name` - The name of the command to execute
+/// * `args` - A list of positional arguments (as CdcValue items)
+/// * `kwargs` - A map of keyword arguments (as CdcValue items)
+#[cfg(feature = "std")]
+pub fn execute_command_items(command_name: &str, args: CdcList, kwargs: CdcDict) -> Result<Vec<Item>, network::ConnectionError> {
+    execute_command_items_in(command_name, args, kwargs, &ExecutionContext::default())
+}
+
+/// Executes a GOM command whose result is a list of items like [`execute_command_items`], with
+/// the stage, selection and connection of `context`, see [`execute_command_in`]
+#[cfg(feature = "std")]
+pub fn execute_command_items_in(command_name: &str, args: CdcList, kwargs: CdcDict, context: &ExecutionContext) -> Result<Vec<Item>, network::ConnectionError> {
+    Item::list_from_reply(execute_command_in(command_name, args, kwargs, context)?)
+}
+
+/// Executes a GOM command against an explicit stage and selection.
+///
+/// Works like [`execute_command`], but doesn't depend on which stage or items happen to be
+/// current in the application.
+///
+/// # Arguments
+/// * `command_name` - The name of the command to execute
+/// * `args` - A list of positional arguments (as CdcValue items)
+/// * `kwargs` - A map of keyword arguments (as CdcValue items)
+/// * `context` - The stage and selection to execute the command with
+#[cfg(feature = "std")]
+pub fn execute_command_in(command_name: &str, args: CdcList, kwargs: CdcDict, context: &ExecutionContext) -> Result<CdcValue, network::ConnectionError> {
+    context.connection.with(|conn| {
+        let mut params = params::CommandParams {
+            command: command_name,
+            args,
+            kwargs: conn.merge_default_kwargs(kwargs),
+            stage: None,
+            selection: None,
+        };
+        context.write_params(conn, &mut params);
+
+        match &context.idempotency_key {
+            Some(key) => conn.request_idempotent(key, network::Request::COMMAND, params, context.timeout, None),
+            None => conn.request_cancellable(network::Request::COMMAND, params, context.timeout, None),
         }
     })
 }
 
+/// Executes a GOM command, giving up after a timeout or when cancelled.
+///
+/// Works like [`execute_command`], but returns `ConnectionError::Timeout` if no result arrives
+/// within `timeout` and `ConnectionError::Cancelled` as soon as `cancel` is set, e.g. from a UI thread.
+///
+/// # Arguments
+/// * `command_name` - The name of the command to execute
+/// * `args` - A list of positional arguments (as CdcValue items)
+/// * `kwargs` - A map of keyword arguments (as CdcValue items)
+/// * `timeout` - The maximum time to wait for the result
+/// * `cancel` - Optional flag that aborts waiting once it is set to true
+#[cfg(feature = "std")]
+pub fn execute_command_timeout(command_name: &str, args: CdcList, kwargs: CdcDict, timeout: Duration, cancel: Option<&AtomicBool>) -> Result<CdcValue, network::ConnectionError> {
+    ConnectionHandle::Global.with(|conn| {
+        let params = params::CommandParams {
+            command: command_name,
+            args,
+            kwargs: conn.merge_default_kwargs(kwargs),
+            stage: None,
+            selection: None,
+        };
+
+        conn.request_cancellable(network::Request::COMMAND, params, Some(timeout), cancel)
+    })
+}
+
+/// Replaces the logging configuration of the active GOM connection.
+///
+/// # Arguments
+/// * `config` - The log levels per request kind and the payload truncation length
+#[cfg(feature = "std")]
+pub fn set_log_config(config: LogConfig) -> Result<(), network::ConnectionError> {
+    ConnectionHandle::Global.with(|conn| {
+        conn.set_log_config(config);
+        Ok(())
+    })
+}
+
+/// Sets the stage substituted for items whose stage is [`CURRENT_STAGE`].
+///
+/// # Arguments
+/// * `stage` - The default stage, or [`Stage::Current`] to let the server decide
+#[cfg(feature = "std")]
+pub fn set_default_stage(stage: Stage) -> Result<(), network::ConnectionError> {
+    ConnectionHandle::Global.with(|conn| {
+        conn.set_default_stage(stage);
+        Ok(())
+    })
+}
+
+/// Sets keyword arguments added to every command executed through the active GOM connection.
+///
+/// Keyword arguments passed to [`execute_command`] take precedence over the defaults on key collision.
+///
+/// # Arguments
+/// * `kwargs` - The default keyword arguments, replacing any set before
+#[cfg(feature = "std")]
+pub fn set_default_kwargs(kwargs: CdcDict) -> Result<(), network::ConnectionError> {
+    ConnectionHandle::Global.with(|conn| {
+        conn.set_default_kwargs(kwargs);
+        Ok(())
+    })
+}
+
+/// Caches up to `capacity` translations of [`tr`] on the active GOM connection.
+///
+/// The cache is disabled by default; a capacity of 0 disables it again.
+///
+/// # Arguments
+/// * `capacity` - The maximum number of cached translations
+#[cfg(feature = "std")]
+pub fn set_translation_cache_capacity(capacity: usize) -> Result<(), network::ConnectionError> {
+    ConnectionHandle::Global.with(|conn| {
+        conn.set_translation_cache_capacity(capacity);
+        Ok(())
+    })
+}
+
+/// Forgets the translations cached on the active GOM connection, e.g. after the application language changed.
+#[cfg(feature = "std")]
+pub fn clear_translation_cache() {
+    // Without a connection there is no cache to clear
+    let _ = ConnectionHandle::Global.with(|conn| {
+        conn.clear_translation_cache();
+        Ok(())
+    });
+}
+
 /// Translates the given text using the GOM application's translation system.
 ///
 /// This function retrieves the translated version of a text string from the running ZEISS Inspect
@@ -137,48 +529,259 @@ pub fn execute_command(command_name: &str, args: CdcList, kwargs: CdcDict) -> Re
 ///
 /// # Arguments
 /// * `text` - The text to be translated
-/// * `id` - Optional translation ID used by the GOM internal translation process
+/// * `id` - Optional translation ID used by the GOM internal translation process; `None` is sent
+///   as NONE, so the server can tell it apart from an empty ID
 ///
 /// # Returns
 /// The translated text, or the original text if translation fails or is unavailable
+#[cfg(feature = "std")]
 pub fn tr(text: &str, id: Option<&str>) -> String {
-    GOM_CONNECTION.with(|conn_cell| {
-        let mut conn_guard = conn_cell.borrow_mut();
-        
-        if let Some(conn) = conn_guard.as_mut() {
-            let mut params = std::collections::HashMap::new();
-            params.insert("text".to_string(), CdcValue::STRING(text.to_string()));
-            params.insert(
-                "id".to_string(),
-                CdcValue::STRING(id.unwrap_or("").to_string()),
-            );
-            
-            match conn.request(network::Request::TRANSLATE, params) {
-                Ok(result) => {
-                    if let CdcValue::MAP(mut result_map) = result {
-                        if let Some(CdcValue::STRING(translation)) = result_map.remove("translation") {
-                            return translation;
-                        }
-                    }
-                }
-                Err(_e) => {
-                    log::warn!("Translation request failed, returning original text");
-                }
-            }
-        } else {
+    tr_in(text, id, &ConnectionHandle::Global)
+}
+
+/// Translates a text like [`tr`] on the given connection
+#[cfg(feature = "std")]
+pub fn tr_in(text: &str, id: Option<&str>, connection: &ConnectionHandle) -> String {
+    match tr_checked_in(text, id, connection) {
+        Ok(translation) => translation,
+        Err(network::ConnectionError::NotConnected) => {
             log::debug!("No GOM connection available, returning original text");
+            text.to_string()
+        }
+        Err(network::ConnectionError::UnexpectedReply(_)) => {
+            log::warn!("Unexpected reply to a translation request, returning original text");
+            text.to_string()
+        }
+        Err(_e) => {
+            log::warn!("Translation request failed, returning original text");
+            text.to_string()
+        }
+    }
+}
+
+/// Translates a text like [`tr`], but reports failures instead of falling back to the original text.
+///
+/// A text without translation still yields `Ok` with the original text.
+///
+/// # Returns
+/// The translated text, or `ConnectionError::UnexpectedReply` if the reply is not a map with a `translation` string
+#[cfg(feature = "std")]
+pub fn tr_checked(text: &str, id: Option<&str>) -> Result<String, network::ConnectionError> {
+    tr_checked_in(text, id, &ConnectionHandle::Global)
+}
+
+/// Translates a text like [`tr_checked`] on the given connection
+#[cfg(feature = "std")]
+pub fn tr_checked_in(text: &str, id: Option<&str>, connection: &ConnectionHandle) -> Result<String, network::ConnectionError> {
+    connection.with(|conn| {
+        if let Some(translation) = conn.cached_translation(text, id) {
+            return Ok(translation);
+        }
+        let mut result_map = CdcDict::try_from(conn.request(network::Request::TRANSLATE, params::TranslateParams { text, id })?)
+            .map_err(|other| network::UnexpectedReply::error(CdcType::MAP, &other))?;
+        match result_map.remove("translation") {
+            Some(CdcValue::STRING(translation)) => {
+                conn.cache_translation(text, id, &translation);
+                Ok(translation)
+            }
+            // No translation available
+            Some(CdcValue::NONE) => Ok(text.to_string()),
+            other => Err(network::UnexpectedReply::error(CdcType::STRING, other.as_ref().unwrap_or(&CdcValue::NONE))),
         }
-        
-        text.to_string()
     })
 }
 
+/// Resolves a script path like `gom.app.project.elements['Surface 1']` into an item.
+///
+/// Plain attribute paths below `gom.` are API items whose id is the path itself, so they are
+/// returned without a round trip like in [`Item::repr`]. Other paths are evaluated with a query.
+///
+/// # Arguments
+/// * `path` - The script path of the item
+///
+/// # Returns
+/// The item in the current stage, or `ConnectionError::UnexpectedReply` if the path doesn't denote an item
+#[cfg(feature = "std")]
+pub fn resolve_item(path: &str) -> Result<Item, network::ConnectionError> {
+    resolve_item_in(path, &ConnectionHandle::Global)
+}
+
+/// Resolves a script path into an item like [`resolve_item`] on the given connection
+#[cfg(feature = "std")]
+pub fn resolve_item_in(path: &str, connection: &ConnectionHandle) -> Result<Item, network::ConnectionError> {
+    let is_api_path = path.starts_with("gom.") && path.split('.').all(|part| {
+        !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    });
+    if is_api_path {
+        return Ok(Item::new(path.to_string(), 0, CURRENT_STAGE));
+    }
+    connection.with(|conn| {
+        Item::from_reply(conn.request(network::Request::QUERY, params::QueryParams { expression: path })?)
+    })
+}
+
+
+/// A connection to ZEISS Inspect with the API as methods.
+///
+/// The session owns its connection and doesn't touch the GOM connection of the current thread, so
+/// the free functions and [`Item`] methods only use it inside [`GomSession::enter`] or after
+/// [`GomSession::install`]. Dropping the session releases the interpreter and disconnects.
+/// Scripts started by ZEISS Inspect usually call [`initialize_gom_connection`] instead.
+#[cfg(feature = "std")]
+pub struct GomSession {
+    /// Only `None` while the session is dropped or installed
+    connection: Option<SharedConnection>,
+}
+
+#[cfg(feature = "std")]
+impl GomSession {
+    /// Connects to the server at `url` and registers the interpreter.
+    ///
+    /// The URL has the format of `TOM_PYTHON_API_URL`, i.e. the API key and interpreter id are
+    /// passed as the `apikey` and `interpreter_id` query parameters.
+    ///
+    /// # Arguments
+    /// * `url` - The WebSocket URL of the server
+    pub fn connect(url: &str) -> Result<Self, network::ConnectionError> {
+        Self::connect_timeout(url, DEFAULT_INIT_TIMEOUT)
+    }
+
+    /// Connects like [`GomSession::connect`], with a custom connect and register timeout.
+    ///
+    /// # Arguments
+    /// * `url` - The WebSocket URL of the server
+    /// * `timeout` - The time allowed for connecting and for registering the interpreter each
+    pub fn connect_timeout(url: &str, timeout: Duration) -> Result<Self, network::ConnectionError> {
+        Self::connect_with(url, ConnectOptions { timeout, ..ConnectOptions::default() })
+    }
+
+    /// Connects like [`GomSession::connect`], registering as `interpreter_id` instead of the id of the URL.
+    ///
+    /// # Arguments
+    /// * `url` - The WebSocket URL of the server
+    /// * `interpreter_id` - The id to register the interpreter with
+    pub fn connect_with_interpreter_id(url: &str, interpreter_id: &str) -> Result<Self, network::ConnectionError> {
+        Self::connect_with(url, ConnectOptions { interpreter_id: Some(interpreter_id.to_string()), ..ConnectOptions::default() })
+    }
+
+    /// Connects like [`GomSession::connect`], with custom options, e.g. the interpreter id.
+    ///
+    /// # Arguments
+    /// * `url` - The WebSocket URL of the server
+    /// * `options` - The timeout and interpreter id to connect with
+    pub fn connect_with(url: &str, options: ConnectOptions) -> Result<Self, network::ConnectionError> {
+        let config = parse_connection_config(url).map_err(|e| network::ConnectionError::Connect(e.to_string()))?;
+        Self::connect_config(config.with_options(options))
+    }
+
+    fn connect_config(config: ConnectionConfig) -> Result<Self, network::ConnectionError> {
+        let mut conn = Connection::init_timeout(&config.server_url, config.api_key, config.timeout)?;
+        conn.register_timeout(&config.interpreter_id, &script_file_path(), config.timeout)?;
+        Ok(GomSession { connection: Some(Arc::new(Mutex::new(conn))) })
+    }
+
+    /// Returns a handle to the connection of the session, e.g. for the `_in` functions.
+    ///
+    /// While handles exist, dropping the session leaves the connection open for them, and
+    /// [`GomSession::install`] fails.
+    pub fn connection(&self) -> ConnectionHandle {
+        ConnectionHandle::Explicit(self.connection.clone().expect("the connection is only taken when the session is consumed"))
+    }
 
+    /// Runs `f` with the free functions and [`Item`] methods using the connection of the session,
+    /// see [`ConnectionHandle::enter`]
+    pub fn enter<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.connection().enter(f)
+    }
+
+    /// Installs the connection as the GOM connection of the current thread, replacing one that
+    /// already exists, so the free functions and [`Item`] methods use it like after
+    /// [`initialize_gom_connection`].
+    ///
+    /// Returns the session back if handles from [`GomSession::connection`] still share the connection.
+    pub fn install(mut self) -> Result<(), GomSession> {
+        let shared = self.connection.take().expect("the connection is only taken when the session is consumed");
+        match Arc::try_unwrap(shared) {
+            Ok(conn) => {
+                install_gom_connection(conn.into_inner().unwrap_or_else(PoisonError::into_inner));
+                Ok(())
+            }
+            Err(shared) => {
+                self.connection = Some(shared);
+                Err(self)
+            }
+        }
+    }
+
+    /// Returns the id the interpreter is registered with, see [`Connection::interpreter_id`]
+    pub fn interpreter_id(&self) -> Result<String, network::ConnectionError> {
+        self.connection().with(|conn| Ok(conn.interpreter_id().to_string()))
+    }
+
+    /// Executes a GOM command, see [`execute_command`]
+    pub fn command(&self, command_name: &str, args: CdcList, kwargs: CdcDict) -> Result<CdcValue, network::ConnectionError> {
+        execute_command_in(command_name, args, kwargs, &ExecutionContext { connection: self.connection(), ..ExecutionContext::default() })
+    }
+
+    /// Translates a text, see [`tr`]
+    pub fn tr(&self, text: &str, id: Option<&str>) -> String {
+        tr_in(text, id, &self.connection())
+    }
+
+    /// Evaluates a query expression on the server, e.g. an element path, and returns its value
+    pub fn query(&self, expression: &str) -> Result<CdcValue, network::ConnectionError> {
+        self.connection().with(|conn| {
+            conn.request(network::Request::QUERY, params::QueryParams { expression })
+        })
+    }
+
+    /// Resolves a script path into an item, see [`resolve_item`]
+    pub fn resolve_item(&self, path: &str) -> Result<Item, network::ConnectionError> {
+        resolve_item_in(path, &self.connection())
+    }
+
+    /// Returns the item with the given id in the current stage.
+    ///
+    /// Its methods use the connection of the session inside [`GomSession::enter`], e.g.
+    /// `session.enter(|| session.item("element").get_attr("name"))`.
+    pub fn item(&self, id: &str) -> Item {
+        Item::new(id.to_string(), 0, CURRENT_STAGE)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::fmt::Debug for GomSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "GomSession({:?})", self.connection())
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for GomSession {
+    /// Releases the interpreter and closes the connection, unless it was installed or handles to it remain
+    fn drop(&mut self) {
+        let Some(shared) = self.connection.take() else {
+            return;
+        };
+        // Handles that outlive the session keep using the connection
+        if let Ok(conn) = Arc::try_unwrap(shared) {
+            if let Err(e) = conn.into_inner().unwrap_or_else(PoisonError::into_inner).close() {
+                log::warn!("Failed to release the interpreter: {:?}", e);
+            }
+        }
+    }
+}
+
+
+/// Represents an error value raised by the GOM application.
 #[derive(Debug, Clone, PartialEq)]
-struct CdcError {
-    id: String,
-    text: String,
-    line: i64,
+pub struct CdcError {
+    /// The identifier of the error.
+    pub id: String,
+    /// The error message.
+    pub text: String,
+    /// The script line the error occurred in.
+    pub line: i64,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -186,17 +789,38 @@ struct Command{
     name: String,
 }
 
+/// A 3D vector, e.g. a point or direction of an element
 #[derive(Debug, Clone, PartialEq)]
-struct Vec3d{
-    x: f64,
-    y: f64,
-    z: f64,
+pub struct Vec3d{
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
 }
 
+impl Vec3d {
+    /// Compares the vectors component-wise, allowing each component to differ by up to `epsilon`
+    pub fn approx_eq(&self, other: &Vec3d, epsilon: f64) -> bool {
+        float_approx_eq(self.x, other.x, epsilon) && float_approx_eq(self.y, other.y, epsilon) && float_approx_eq(self.z, other.z, epsilon)
+    }
+}
+
+/// A 2D vector, e.g. a point in an image
 #[derive(Debug, Clone, PartialEq)]
-struct Vec2d{
-    x: f64,
-    y: f64,
+pub struct Vec2d{
+    pub x: f64,
+    pub y: f64,
+}
+
+impl Vec2d {
+    /// Compares the vectors component-wise, allowing each component to differ by up to `epsilon`
+    pub fn approx_eq(&self, other: &Vec2d, epsilon: f64) -> bool {
+        float_approx_eq(self.x, other.x, epsilon) && float_approx_eq(self.y, other.y, epsilon)
+    }
+}
+
+/// Equality of floats with an absolute tolerance; equal infinities match, NaN never does
+pub(crate) fn float_approx_eq(a: f64, b: f64, epsilon: f64) -> bool {
+    a == b || (a - b).abs() <= epsilon
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -205,59 +829,198 @@ struct Vec2d{
 /// An Item has a unique ID, belongs to a category, and is associated with a stage.
 /// It provides methods for accessing and manipulating item attributes, filtering data,
 /// and communicating with the server via WebSocket requests.
+///
+/// `PartialEq`, `Hash` and the fast path of [`Item::equals`] all take the stage into account,
+/// so items that compare equal always hash equally.
 pub struct Item {
     /// The unique identifier of the item.
     pub id: String,
     /// The category this item belongs to.
     pub category: i32,
-    /// The stage this item is associated with.
+    /// The raw stage this item is associated with, or [`CURRENT_STAGE`], see [`Item::stage_enum`].
     pub stage: i32,
 }
 
-impl Item {
-    /// Creates a new Item with the specified ID, category, and stage.
+/// Stage value meaning "the current stage of the project".
+///
+/// When an item with this stage is sent, the connection substitutes its default stage
+/// (see [`set_default_stage`]); if none is set, the server uses its current stage.
+pub const CURRENT_STAGE: i32 = -1;
+
+/// Named view of the raw stage values carried by [`Item::stage`].
+///
+/// Replaces the negative sentinels with variants, so [`CURRENT_STAGE`] can't be mistaken for
+/// a stage index. Converts to and from the raw `i32` and the `i64` sent on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Stage {
+    /// The current stage of the project ([`CURRENT_STAGE`]).
+    #[default]
+    Current,
+    /// The stage with this index.
+    Index(u32),
+}
+
+/// A raw stage value that is neither [`CURRENT_STAGE`] nor a stage index an item can hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidStage(pub i64);
+
+impl core::fmt::Display for InvalidStage {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Invalid stage {}", self.0)
+    }
+}
+
+impl core::error::Error for InvalidStage {}
+
+impl From<Stage> for i64 {
+    fn from(stage: Stage) -> Self {
+        match stage {
+            Stage::Current => CURRENT_STAGE.into(),
+            Stage::Index(index) => index.into(),
+        }
+    }
+}
+
+impl TryFrom<i64> for Stage {
+    type Error = InvalidStage;
+
+    /// Fails for negative values other than [`CURRENT_STAGE`] and indices beyond `u32::MAX`.
+    fn try_from(stage: i64) -> Result<Self, Self::Error> {
+        match stage {
+            stage if stage == i64::from(CURRENT_STAGE) => Ok(Stage::Current),
+            index => u32::try_from(index).map(Stage::Index).map_err(|_| InvalidStage(stage)),
+        }
+    }
+}
+
+impl TryFrom<Stage> for i32 {
+    type Error = InvalidStage;
+
+    /// Fails for indices beyond `i32::MAX`, which the raw stage of an item can't hold.
+    fn try_from(stage: Stage) -> Result<Self, Self::Error> {
+        match stage {
+            Stage::Current => Ok(CURRENT_STAGE),
+            Stage::Index(index) => i32::try_from(index).map_err(|_| InvalidStage(index.into())),
+        }
+    }
+}
+
+impl TryFrom<i32> for Stage {
+    type Error = InvalidStage;
+
+    fn try_from(stage: i32) -> Result<Self, Self::Error> {
+        Stage::try_from(i64::from(stage))
+    }
+}
+
+impl Default for Item {
+    /// A placeholder item with an empty id, the default category and [`CURRENT_STAGE`]
+    fn default() -> Self {
+        Item::new(String::new(), 0, CURRENT_STAGE)
+    }
+}
+
+impl Item {
+    /// Creates a new Item with the specified ID, category, and stage.
+    ///
+    /// # Arguments
+    /// * `id` - The unique identifier for this item
+    /// * `category` - The category this item belongs to (default: 0)
+    /// * `stage` - The stage this item is associated with (default: [`CURRENT_STAGE`])
+    pub fn new(id: String, category: i32, stage: i32) -> Self {
+        Item { id, category, stage }
+    }
+
+    /// Returns the stage of this item as a [`Stage`], or `None` if the raw stage is invalid.
+    pub fn stage_enum(&self) -> Option<Stage> {
+        Stage::try_from(self.stage).ok()
+    }
+
+    /// Returns this item in another stage, failing if the stage index doesn't fit the raw stage.
+    pub fn in_stage(&self, stage: Stage) -> Result<Item, InvalidStage> {
+        Ok(Item { stage: stage.try_into()?, ..self.clone() })
+    }
+
+    /// Writes the transmission map of this Item into a caller-owned map.
+    ///
+    /// Existing `id`, `category` and `stage` entries are overwritten in place so their
+    /// allocations are reused; any other entries are removed.
+    ///
+    /// # Arguments
+    /// * `map` - The map to fill
+    pub fn write_to_map(&self, map: &mut CdcDict) {
+        map.retain(|key, _| matches!(key.as_str(), "id" | "category" | "stage"));
+        match map.get_mut("id") {
+            Some(CdcValue::STRING(id)) => {
+                id.clear();
+                id.push_str(&self.id);
+            }
+            _ => {
+                map.insert("id".to_string(), CdcValue::STRING(self.id.clone()));
+            }
+        }
+        match map.get_mut("category") {
+            Some(category) => *category = CdcValue::INTEGER(encoding::cat_to_wire(self.category)),
+            None => {
+                map.insert("category".to_string(), CdcValue::INTEGER(encoding::cat_to_wire(self.category)));
+            }
+        }
+        match map.get_mut("stage") {
+            Some(stage) => *stage = CdcValue::INTEGER(encoding::cat_to_wire(self.stage)),
+            None => {
+                map.insert("stage".to_string(), CdcValue::INTEGER(encoding::cat_to_wire(self.stage)));
+            }
+        }
+    }
+
+    /// Creates a JSON representation of this item.
+    pub fn to_json(&self) -> HashMap<String, CdcValue> {
+        let mut map = HashMap::new();
+        map.insert("id".to_string(), CdcValue::STRING(self.id.clone()));
+        map.insert("category".to_string(), CdcValue::INTEGER(encoding::cat_to_wire(self.category)));
+        map.insert("stage".to_string(), CdcValue::INTEGER(encoding::cat_to_wire(self.stage)));
+        map
+    }
+
+    /// Creates an API JSON representation of this item (for protocol messages).
+    pub fn to_api_json(&self) -> HashMap<String, CdcValue> {
+        let mut map = HashMap::new();
+        map.insert("$type".to_string(), CdcValue::STRING("reference".to_string()));
+        map.insert("id".to_string(), CdcValue::STRING(self.id.clone()));
+        map.insert("category".to_string(), CdcValue::INTEGER(encoding::cat_to_wire(self.category)));
+        map
+    }
+
+    /// Creates the API reference of this item as a value ready to be used as a parameter.
     ///
-    /// # Arguments
-    /// * `id` - The unique identifier for this item
-    /// * `category` - The category this item belongs to (default: 0)
-    /// * `stage` - The stage this item is associated with (default: -1)
-    pub fn new(id: String, category: i32, stage: i32) -> Self {
-        Item { id, category, stage }
+    /// This is [`Item::to_api_json`] wrapped in `CdcValue::MAP`, including the `$type` entry.
+    pub fn as_reference(&self) -> CdcValue {
+        CdcValue::MAP(self.to_api_json())
     }
+}
 
-    /// Retrieves the value of an attribute from this item.
+#[cfg(feature = "std")]
+impl Item {
+    /// Retrieves the value of an attribute from this item (`GET` request).
+    ///
+    /// The `index` selects an element of an array-like attribute on the server, like
+    /// `item.get('name', index=0)` in Python.
     ///
     /// # Arguments
     /// * `key` - The name of the attribute to retrieve
     /// * `index` - Optional index for accessing array-like attributes
     pub fn get(&self, key: &str, index: Option<i64>) -> Result<CdcValue, network::ConnectionError> {
-        GOM_CONNECTION.with(|conn_cell| {
-            let mut conn_guard = conn_cell.borrow_mut();
-            if let Some(conn) = conn_guard.as_mut() {
-                let mut params = HashMap::new();
-                params.insert("item".to_string(), CdcValue::MAP(self.to_map()?));
-                params.insert("name".to_string(), CdcValue::STRING(key.to_string()));
-                if let Some(idx) = index {
-                    params.insert("index".to_string(), CdcValue::INTEGER(idx));
-                }
-                conn.request(network::Request::GET, params)
-            } else {
-                Err(network::ConnectionError::Request)
-            }
+        ConnectionHandle::Global.with(|conn| {
+            let params = params::GetParams { item: conn.item_map(self), name: key, index };
+            conn.item_request(self, network::Request::GET, params)
         })
     }
 
     /// Retrieves all available tokens for this item.
     pub fn get_tokens(&self) -> Result<CdcValue, network::ConnectionError> {
-        GOM_CONNECTION.with(|conn_cell| {
-            let mut conn_guard = conn_cell.borrow_mut();
-            if let Some(conn) = conn_guard.as_mut() {
-                let mut params = HashMap::new();
-                params.insert("item".to_string(), CdcValue::MAP(self.to_map()?));
-                conn.request(network::Request::TOKENS, params)
-            } else {
-                Err(network::ConnectionError::Request)
-            }
+        ConnectionHandle::Global.with(|conn| {
+            let params = params::ItemParams { item: conn.item_map(self) };
+            conn.item_request(self, network::Request::TOKENS, params)
         })
     }
 
@@ -267,60 +1030,51 @@ impl Item {
     /// * `expression` - The filter expression to apply
     /// * `condition` - Optional filter condition
     pub fn filter(&self, expression: &str, condition: Option<&str>) -> Result<CdcValue, network::ConnectionError> {
-        GOM_CONNECTION.with(|conn_cell| {
-            let mut conn_guard = conn_cell.borrow_mut();
-            if let Some(conn) = conn_guard.as_mut() {
-                let mut params = HashMap::new();
-                params.insert("item".to_string(), CdcValue::MAP(self.to_map()?));
-                params.insert("expression".to_string(), CdcValue::STRING(expression.to_string()));
-                if let Some(cond) = condition {
-                    params.insert("condition".to_string(), CdcValue::STRING(cond.to_string()));
-                }
-                conn.request(network::Request::FILTER, params)
-            } else {
-                Err(network::ConnectionError::Request)
-            }
+        ConnectionHandle::Global.with(|conn| {
+            let params = params::FilterParams { item: conn.item_map(self), expression, condition };
+            conn.item_request(self, network::Request::FILTER, params)
         })
     }
 
+    /// Filters this item like [`Item::filter`], for filters that yield a list of items.
+    ///
+    /// Returns `ConnectionError::UnexpectedReply` if the result is not a list or contains anything
+    /// other than items or item parameter maps.
+    ///
+    /// # Arguments
+    /// * `expression` - The filter expression to apply
+    /// * `condition` - Optional filter condition
+    pub fn filter_items(&self, expression: &str, condition: Option<&str>) -> Result<Vec<Item>, network::ConnectionError> {
+        Item::list_from_reply(self.filter(expression, condition)?)
+    }
+
     /// Compares this item with another using the less-than operator.
     pub fn less_than(&self, other: &Item) -> Result<bool, network::ConnectionError> {
-        GOM_CONNECTION.with(|conn_cell| {
-            let mut conn_guard = conn_cell.borrow_mut();
-            if let Some(conn) = conn_guard.as_mut() {
-                let mut params = HashMap::new();
-                params.insert("item".to_string(), CdcValue::MAP(self.to_map()?));
-                params.insert("other".to_string(), CdcValue::MAP(other.to_map()?));
-                match conn.request(network::Request::LESS, params)? {
-                    CdcValue::BOOL(result) => Ok(result),
-                    _ => Err(network::ConnectionError::Request),
-                }
-            } else {
-                Err(network::ConnectionError::Request)
+        ConnectionHandle::Global.with(|conn| {
+            let params = params::CompareParams { item: conn.item_map(self), other: conn.item_map(other) };
+            match conn.request(network::Request::LESS, params)? {
+                CdcValue::BOOL(result) => Ok(result),
+                other => Err(network::UnexpectedReply::error(CdcType::BOOLEAN, &other)),
             }
         })
     }
 
     /// Checks if this item equals another item.
+    ///
+    /// Items with the same ID and category but different stages represent different
+    /// snapshots, so only fully identical items (`self == other`) are equal without asking the server.
     pub fn equals(&self, other: &Item) -> Result<bool, network::ConnectionError> {
-        // Fast path: compare by ID and category for same items
-        if self.category == other.category && self.id == other.id {
+        // Fast path: identical ID, category and stage
+        if self == other {
             return Ok(true);
         }
         
         // Server-side comparison for different items
-        GOM_CONNECTION.with(|conn_cell| {
-            let mut conn_guard = conn_cell.borrow_mut();
-            if let Some(conn) = conn_guard.as_mut() {
-                let mut params = HashMap::new();
-                params.insert("item".to_string(), CdcValue::MAP(self.to_map()?));
-                params.insert("other".to_string(), CdcValue::MAP(other.to_map()?));
-                match conn.request(network::Request::EQUAL, params)? {
-                    CdcValue::BOOL(result) => Ok(result),
-                    _ => Err(network::ConnectionError::Request),
-                }
-            } else {
-                Err(network::ConnectionError::Request)
+        ConnectionHandle::Global.with(|conn| {
+            let params = params::CompareParams { item: conn.item_map(self), other: conn.item_map(other) };
+            match conn.request(network::Request::EQUAL, params)? {
+                CdcValue::BOOL(result) => Ok(result),
+                other => Err(network::UnexpectedReply::error(CdcType::BOOLEAN, &other)),
             }
         })
     }
@@ -330,17 +1084,84 @@ impl Item {
     /// # Arguments
     /// * `name` - The name of the attribute to access
     pub fn get_attr(&self, name: &str) -> Result<CdcValue, network::ConnectionError> {
-        GOM_CONNECTION.with(|conn_cell| {
-            let mut conn_guard = conn_cell.borrow_mut();
-            if let Some(conn) = conn_guard.as_mut() {
-                let mut params = HashMap::new();
-                params.insert("item".to_string(), CdcValue::MAP(self.to_map()?));
-                params.insert("name".to_string(), CdcValue::STRING(name.to_string()));
-                params.insert("stage".to_string(), CdcValue::INTEGER(self.stage as i64));
-                conn.request(network::Request::GETATTR, params)
-            } else {
-                Err(network::ConnectionError::Request)
-            }
+        ConnectionHandle::Global.with(|conn| {
+            let stage = conn.item_stage(self);
+            let params = params::GetAttrParams { item: conn.item_map(self), name, stage };
+            conn.item_request(self, network::Request::GETATTR, params)
+        })
+    }
+
+    /// Accesses several attributes of this item at once.
+    ///
+    /// The requests are pipelined, so the round trips overlap instead of adding up.
+    ///
+    /// # Arguments
+    /// * `names` - The names of the attributes to access
+    pub fn get_attrs(&self, names: &[&str]) -> Result<HashMap<String, CdcValue>, network::ConnectionError> {
+        ConnectionHandle::Global.with(|conn| {
+            let stage = conn.item_stage(self);
+            let requests = names
+                .iter()
+                .map(|name| {
+                    let params = params::GetAttrParams { item: conn.item_map(self), name, stage };
+                    (network::Request::GETATTR, params.into_params())
+                })
+                .collect();
+            names
+                .iter()
+                .zip(conn.request_batch(requests))
+                .map(|(name, result)| result.map(|value| (name.to_string(), value)))
+                .collect()
+        })
+    }
+
+    /// Returns the GOM type of this item, e.g. `inspection.point`, read from its `type` attribute
+    pub fn type_name(&self) -> Result<String, network::ConnectionError> {
+        self.get_string("type")
+    }
+
+    /// Reads a BOOLEAN attribute of this item, failing with `ConnectionError::UnexpectedReply` for other types
+    pub fn get_bool(&self, name: &str) -> Result<bool, network::ConnectionError> {
+        self.get_attr_as(name, CdcType::BOOLEAN)
+    }
+
+    /// Reads an INTEGER attribute of this item, failing with `ConnectionError::UnexpectedReply` for other types
+    pub fn get_int(&self, name: &str) -> Result<i64, network::ConnectionError> {
+        self.get_attr_as(name, CdcType::INTEGER)
+    }
+
+    /// Reads a FLOAT attribute of this item, failing with `ConnectionError::UnexpectedReply` for other types
+    pub fn get_float(&self, name: &str) -> Result<f64, network::ConnectionError> {
+        self.get_attr_as(name, CdcType::FLOAT)
+    }
+
+    /// Reads a STRING attribute of this item, failing with `ConnectionError::UnexpectedReply` for other types
+    pub fn get_string(&self, name: &str) -> Result<String, network::ConnectionError> {
+        self.get_attr_as(name, CdcType::STRING)
+    }
+
+    fn get_attr_as<T: TryFrom<CdcValue, Error = CdcValue>>(&self, name: &str, expected: CdcType) -> Result<T, network::ConnectionError> {
+        T::try_from(self.get_attr(name)?)
+            .map_err(|value| network::UnexpectedReply::error(expected, &value))
+    }
+
+    /// Reads a data interface attribute of this item, e.g. a nominal or actual value or a tolerance.
+    ///
+    /// Unlike `get_attr`, which reads script attributes of the element, this goes through the data
+    /// interface (`DATA_ATTR`), which returns the measurement data of one stage. Use it for the
+    /// values listed under `data` in the element properties.
+    ///
+    /// # Arguments
+    /// * `name` - The name of the data attribute
+    /// * `stage` - The stage to read, the stage of the item if `None`
+    pub fn data_attr(&self, name: &str, stage: Option<Stage>) -> Result<CdcValue, network::ConnectionError> {
+        ConnectionHandle::Global.with(|conn| {
+            let stage = match stage {
+                Some(stage) => i64::from(conn.resolve_stage(stage)),
+                None => conn.item_stage(self),
+            };
+            let params = params::GetAttrParams { item: conn.item_map(self), name, stage };
+            conn.item_request(self, network::Request::DATA_ATTR, params)
         })
     }
 
@@ -350,52 +1171,57 @@ impl Item {
     /// * `name` - The name of the attribute to set
     /// * `value` - The value to set
     pub fn set_attr(&self, name: &str, value: CdcValue) -> Result<(), network::ConnectionError> {
-        GOM_CONNECTION.with(|conn_cell| {
-            let mut conn_guard = conn_cell.borrow_mut();
-            if let Some(conn) = conn_guard.as_mut() {
-                let mut params = HashMap::new();
-                params.insert("item".to_string(), CdcValue::MAP(self.to_map()?));
-                params.insert("name".to_string(), CdcValue::STRING(name.to_string()));
-                params.insert("value".to_string(), value);
-                conn.request(network::Request::SETATTR, params)?;
-                Ok(())
-            } else {
-                Err(network::ConnectionError::Request)
-            }
+        self.set_attr_returning(name, value).map(|_| ())
+    }
+
+    /// Sets an attribute of this item and returns the reply of the server.
+    ///
+    /// The reply holds the value as the server stored it, e.g. after a unit conversion, so callers
+    /// can check that the value round-tripped. Attributes whose setter has no result reply NONE.
+    ///
+    /// # Arguments
+    /// * `name` - The name of the attribute to set
+    /// * `value` - The value to set
+    pub fn set_attr_returning(&self, name: &str, value: CdcValue) -> Result<CdcValue, network::ConnectionError> {
+        ConnectionHandle::Global.with(|conn| {
+            let params = params::SetAttrParams { item: conn.item_map(self), name, value };
+            conn.item_request(self, network::Request::SETATTR, params)
         })
     }
 
-    /// Accesses an item by key (indexing operator).
+    /// Accesses an item by key, like `item['key']` in Python (`KEY` request).
+    ///
+    /// Use [`Item::get_index`] for integer indices like `item[0]`.
     ///
     /// # Arguments
     /// * `key` - The key to access
     pub fn get_item(&self, key: &str) -> Result<CdcValue, network::ConnectionError> {
-        GOM_CONNECTION.with(|conn_cell| {
-            let mut conn_guard = conn_cell.borrow_mut();
-            if let Some(conn) = conn_guard.as_mut() {
-                let mut params = HashMap::new();
-                params.insert("item".to_string(), CdcValue::MAP(self.to_map()?));
-                params.insert("name".to_string(), CdcValue::STRING(key.to_string()));
-                conn.request(network::Request::KEY, params)
-            } else {
-                Err(network::ConnectionError::Request)
-            }
+        ConnectionHandle::Global.with(|conn| {
+            let params = params::KeyParams { item: conn.item_map(self), name: key };
+            conn.item_request(self, network::Request::KEY, params)
+        })
+    }
+
+    /// Accesses an element by its integer index, like `item[0]` in Python (`INDEX` request).
+    ///
+    /// Use [`Item::get_item`] for string keys like `item['key']`.
+    ///
+    /// # Arguments
+    /// * `index` - The index to access, negative indices count from the end like in Python
+    pub fn get_index(&self, index: i64) -> Result<CdcValue, network::ConnectionError> {
+        ConnectionHandle::Global.with(|conn| {
+            let params = params::IndexParams { item: conn.item_map(self), index };
+            conn.item_request(self, network::Request::INDEX, params)
         })
     }
 
     /// Returns the length of this item.
     pub fn len(&self) -> Result<i64, network::ConnectionError> {
-        GOM_CONNECTION.with(|conn_cell| {
-            let mut conn_guard = conn_cell.borrow_mut();
-            if let Some(conn) = conn_guard.as_mut() {
-                let mut params = HashMap::new();
-                params.insert("item".to_string(), CdcValue::MAP(self.to_map()?));
-                match conn.request(network::Request::LEN, params)? {
-                    CdcValue::INTEGER(len) => Ok(len),
-                    _ => Err(network::ConnectionError::Request),
-                }
-            } else {
-                Err(network::ConnectionError::Request)
+        ConnectionHandle::Global.with(|conn| {
+            let params = params::ItemParams { item: conn.item_map(self) };
+            match conn.item_request(self, network::Request::LEN, params)? {
+                CdcValue::INTEGER(len) => Ok(len),
+                other => Err(network::UnexpectedReply::error(CdcType::INTEGER, &other)),
             }
         })
     }
@@ -412,90 +1238,82 @@ impl Item {
             return Ok(self.id.clone());
         }
 
-        GOM_CONNECTION.with(|conn_cell| {
-            let mut conn_guard = conn_cell.borrow_mut();
-            if let Some(conn) = conn_guard.as_mut() {
-                let mut params = HashMap::new();
-                params.insert("item".to_string(), CdcValue::MAP(self.to_map()?));
-                match conn.request(network::Request::REPR, params)? {
-                    CdcValue::STRING(repr) => Ok(repr),
-                    _ => Err(network::ConnectionError::Request),
-                }
-            } else {
-                Err(network::ConnectionError::Request)
+        ConnectionHandle::Global.with(|conn| {
+            let params = params::ItemParams { item: conn.item_map(self) };
+            match conn.request(network::Request::REPR, params)? {
+                CdcValue::STRING(repr) => Ok(repr),
+                other => Err(network::UnexpectedReply::error(CdcType::STRING, &other)),
             }
         })
     }
 
+    /// Checks whether the server still knows this item.
+    ///
+    /// Unlike accessing an attribute, an unknown item yields `Ok(false)` instead of an error,
+    /// so it can't be confused with other failures.
+    pub fn exists(&self) -> Result<bool, network::ConnectionError> {
+        ConnectionHandle::Global.with(|conn| {
+            conn.item_exists(self)
+        })
+    }
+
     /// Returns the documentation for this item.
     pub fn doc(&self) -> Result<String, network::ConnectionError> {
-        GOM_CONNECTION.with(|conn_cell| {
-            let mut conn_guard = conn_cell.borrow_mut();
-            if let Some(conn) = conn_guard.as_mut() {
-                let mut params = HashMap::new();
-                params.insert("object".to_string(), CdcValue::MAP(self.to_map()?));
-                match conn.request(network::Request::DOC, params)? {
-                    CdcValue::STRING(doc) => Ok(doc),
-                    _ => Err(network::ConnectionError::Request),
-                }
-            } else {
-                Err(network::ConnectionError::Request)
+        ConnectionHandle::Global.with(|conn| {
+            let params = params::DocParams { object: conn.item_map(self) };
+            match conn.request(network::Request::DOC, params)? {
+                CdcValue::STRING(doc) => Ok(doc),
+                other => Err(network::UnexpectedReply::error(CdcType::STRING, &other)),
             }
         })
     }
 
-    /// Converts this Item to a CDC map for transmission.
-    fn to_map(&self) -> Result<HashMap<String, CdcValue>, network::ConnectionError> {
-        let mut map = HashMap::new();
-        map.insert("id".to_string(), CdcValue::STRING(self.id.clone()));
-        map.insert("category".to_string(), CdcValue::INTEGER(self.category as i64));
-        map.insert("stage".to_string(), CdcValue::INTEGER(self.stage as i64));
-        Ok(map)
-    }
-
-    /// Creates a JSON representation of this item.
-    pub fn to_json(&self) -> HashMap<String, CdcValue> {
-        let mut map = HashMap::new();
-        map.insert("id".to_string(), CdcValue::STRING(self.id.clone()));
-        map.insert("category".to_string(), CdcValue::INTEGER(self.category as i64));
-        map.insert("stage".to_string(), CdcValue::INTEGER(self.stage as i64));
-        map
+    /// Converts a reply holding an item or the parameter map of one into the item
+    pub(crate) fn from_reply(value: CdcValue) -> Result<Item, network::ConnectionError> {
+        match value {
+            CdcValue::ITEM(item) => Ok(item),
+            value => match CdcDict::try_from(value) {
+                Ok(params) => Item::from_params(&params),
+                Err(other) => Err(network::UnexpectedReply::error(CdcType::ITEM, &other)),
+            },
+        }
     }
 
-    /// Creates an API JSON representation of this item (for protocol messages).
-    pub fn to_api_json(&self) -> HashMap<String, CdcValue> {
-        let mut map = HashMap::new();
-        map.insert("$type".to_string(), CdcValue::STRING("reference".to_string()));
-        map.insert("id".to_string(), CdcValue::STRING(self.id.clone()));
-        map.insert("category".to_string(), CdcValue::INTEGER(self.category as i64));
-        map
+    /// Converts a reply holding a list of items like `from_reply`
+    pub(crate) fn list_from_reply(value: CdcValue) -> Result<Vec<Item>, network::ConnectionError> {
+        match value {
+            CdcValue::LIST(values) => values.into_iter().map(Item::from_reply).collect(),
+            other => Err(network::UnexpectedReply::error(CdcType::LIST, &other)),
+        }
     }
 
     /// Creates an Item from parameters (typically from server response).
+    ///
+    /// Fails with `ConnectionError::UnexpectedReply` if `id` is missing or not a string, or if
+    /// `category` or `stage` is present but not an integer.
     pub fn from_params(params: &HashMap<String, CdcValue>) -> Result<Self, network::ConnectionError> {
-        let id = params
-            .get("id")
-            .and_then(|v| if let CdcValue::STRING(s) = v { Some(s.clone()) } else { None })
-            .ok_or(network::ConnectionError::Request)?;
-        
-        let category = params
-            .get("category")
-            .and_then(|v| if let CdcValue::INTEGER(i) = v { Some(*i) } else { None })
-            .unwrap_or(0) as i32;
-        
-        let stage = params
-            .get("stage")
-            .and_then(|v| if let CdcValue::INTEGER(i) = v { Some(*i) } else { None })
-            .unwrap_or(-1) as i32;
-        
+        let id = match params.get("id") {
+            Some(CdcValue::STRING(id)) => id.clone(),
+            other => return Err(network::UnexpectedReply::error(CdcType::STRING, other.unwrap_or(&CdcValue::NONE))),
+        };
+
+        let integer = |key: &str, default: i32| match params.get(key) {
+            None => Ok(default),
+            Some(CdcValue::INTEGER(value)) => Ok(encoding::cat_from_wire(*value)?),
+            Some(other) => Err(network::UnexpectedReply::error(CdcType::INTEGER, other)),
+        };
+        let category = integer("category", 0)?;
+        let stage = integer("stage", CURRENT_STAGE)?;
+
         Ok(Item { id, category, stage })
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Default)]
 /// Represents a Python slice object with start and stop values.
 ///
 /// A Slice represents a portion of a sequence, defined by optional start and stop indices.
+/// The default slice has neither, selecting the whole sequence like `[:]`.
 pub struct Slice {
     /// The start index of the slice (None if not specified).
     pub start: Option<i64>,
@@ -517,7 +1335,7 @@ pub struct Indexable {
     pub size: i64,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Default)]
 /// Represents a Trait object from the GOM type system.
 ///
 /// A Trait is a generic type instance with an identifier and arguments.
@@ -546,6 +1364,44 @@ pub struct Object {
     pub attributes: HashMap<String, CdcValue>,
 }
 
+impl Object {
+    /// Creates a new Object without attributes.
+    ///
+    /// # Arguments
+    /// * `type_id` - The type identifier of the object
+    /// * `repr` - The string representation of the object
+    pub fn new(type_id: String, repr: String) -> Self {
+        Object { type_id, repr, attributes: HashMap::new() }
+    }
+
+    /// Adds an attribute, returning the updated object.
+    ///
+    /// # Arguments
+    /// * `name` - The name of the attribute
+    /// * `value` - The value of the attribute
+    pub fn with_attr(mut self, name: &str, value: CdcValue) -> Self {
+        self.attributes.insert(name.to_string(), value);
+        self
+    }
+
+    /// Returns the value of an attribute, if present.
+    pub fn attr(&self, name: &str) -> Option<&CdcValue> {
+        self.attributes.get(name)
+    }
+
+    /// Returns the value of an attribute converted to `T`.
+    ///
+    /// Returns None if the attribute is missing or holds a different type.
+    pub fn attr_as<T: TryFrom<CdcValue>>(&self, name: &str) -> Option<T> {
+        self.attr(name).and_then(|value| T::try_from(value.clone()).ok())
+    }
+
+    /// Iterates over all attributes as name/value pairs.
+    pub fn attrs(&self) -> impl Iterator<Item = (&str, &CdcValue)> {
+        self.attributes.iter().map(|(name, value)| (name.as_str(), value))
+    }
+}
+
 /// Represents a data array container.
 ///
 /// Arrays are used to represent structured data that can be accessed via indexing.
@@ -578,10 +1434,44 @@ pub struct Package {
     pub metadata: CdcDict,
 }
 
+impl Package {
+    /// Creates a new Package reference without metadata.
+    ///
+    /// # Arguments
+    /// * `reference` - The package reference/handle
+    pub fn new(reference: String) -> Self {
+        Package { reference, metadata: HashMap::new() }
+    }
+
+    /// Adds a metadata entry, returning the updated package.
+    ///
+    /// # Arguments
+    /// * `key` - The metadata key
+    /// * `value` - The metadata value
+    pub fn with_metadata(mut self, key: &str, value: CdcValue) -> Self {
+        self.metadata.insert(key.to_string(), value);
+        self
+    }
+
+    /// Returns a metadata value, if present.
+    pub fn metadata_get(&self, key: &str) -> Option<&CdcValue> {
+        self.metadata.get(key)
+    }
+
+    /// Returns a metadata value converted to `T`.
+    ///
+    /// Returns None if the key is missing or holds a different type.
+    pub fn metadata_get_as<T: TryFrom<CdcValue>>(&self, key: &str) -> Option<T> {
+        self.metadata_get(key).and_then(|value| T::try_from(value.clone()).ok())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use alloc::vec;
 
+    #[cfg(feature = "std")]
     #[test]
     fn test_parse_connection_config() {
         // Test with all parameters
@@ -606,9 +1496,24 @@ mod tests {
         assert_eq!(config3.strip_tracebacks, true);
         // interpreter_id should be generated, so not empty
         assert!(!config3.interpreter_id.is_empty());
+
+        // Empty queries and segments are tolerated, parameters without a value are not
+        assert_eq!(parse_connection_config("ws://localhost:41000?").unwrap().api_key, "");
+        assert_eq!(parse_connection_config("ws://localhost:41000?&").unwrap().api_key, "");
+        assert_eq!(parse_connection_config("ws://localhost:41000?apikey=").unwrap().api_key, "");
+        assert_eq!(parse_connection_config("ws://localhost:41000?&apikey=key&&").unwrap().api_key, "key");
+        assert!(parse_connection_config("ws://localhost:41000?apikey").is_err());
+        assert!(parse_connection_config("ws://localhost:41000?apikey=key&interpreter_id").is_err());
+
+        let config = parse_connection_config("ws://localhost:41000?apikey=key&interpreter_id=url").unwrap();
+        assert_eq!(config.clone().with_options(ConnectOptions::default()).interpreter_id, "url");
+        let options = ConnectOptions { timeout: Duration::from_secs(1), interpreter_id: Some("option".to_string()) };
+        let config = config.with_options(options);
+        assert_eq!((config.interpreter_id.as_str(), config.timeout), ("option", Duration::from_secs(1)));
     }
     #[test]
     // Before running this test, ensure that a WebSocket server is running at ws://localhost:3012 that can accept connections with the specified parameters.
+    #[cfg(feature = "std")]
     fn test_initialize_gom_connection() {
 
         // Set environment variable for testing
@@ -619,8 +1524,547 @@ mod tests {
         
         // Check if connection was established (this is a simple check, in real test we'd verify more)
         // For now, just ensure no panic
+        shutdown_gom_connection();
+        assert!(GOM_CONNECTION.with(|conn_cell| conn_cell.borrow().is_none()));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_shutdown_gom_connection() {
+        connect_test_server(&network::test_server::replying(1, |_, _| Ok(CdcValue::NONE)));
+        assert!(execute_command("gom.script.sys.test", vec![], CdcDict::new()).is_ok());
+        shutdown_gom_connection();
+        assert!(matches!(execute_command("gom.script.sys.test", vec![], CdcDict::new()), Err(ConnectionError::NotConnected)));
+        shutdown_gom_connection();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_item_from_params() {
+        let mut params = HashMap::new();
+        params.insert("id".to_string(), CdcValue::STRING("item".to_string()));
+        params.insert("category".to_string(), CdcValue::INTEGER(2));
+        assert_eq!(Item::from_params(&params).unwrap(), Item::new("item".to_string(), 2, CURRENT_STAGE));
+
+        params.insert("stage".to_string(), CdcValue::STRING("1".to_string()));
+        assert!(matches!(
+            Item::from_params(&params),
+            Err(network::ConnectionError::UnexpectedReply(UnexpectedReply { expected_type: CdcType::INTEGER, received_type: CdcType::STRING }))
+        ));
+
+        params.remove("stage");
+        params.insert("id".to_string(), CdcValue::INTEGER(1));
+        assert!(matches!(
+            Item::from_params(&params),
+            Err(network::ConnectionError::UnexpectedReply(UnexpectedReply { expected_type: CdcType::STRING, received_type: CdcType::INTEGER }))
+        ));
+
+        params.remove("id");
+        assert!(matches!(
+            Item::from_params(&params),
+            Err(network::ConnectionError::UnexpectedReply(UnexpectedReply { expected_type: CdcType::STRING, received_type: CdcType::NONE }))
+        ));
+    }
+
+    #[test]
+    fn test_item_write_to_map_reuses_map() {
+        let mut map = CdcDict::new();
+        map.insert("unrelated".to_string(), CdcValue::NONE);
+        Item::new("first".to_string(), 1, 2).write_to_map(&mut map);
+        Item::new("second".to_string(), 3, -1).write_to_map(&mut map);
+
+        assert_eq!(map, Item::new("second".to_string(), 3, -1).to_json());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_item_equals_fast_path_respects_stage() {
+        let item = Item::new("item".to_string(), 1, 0);
+        assert!(item.equals(&item.clone()).unwrap());
+
+        // Different stages are not short-circuited, so without a connection the server comparison fails
+        let other_stage = Item::new("item".to_string(), 1, 1);
+        assert!(item.equals(&other_stage).is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_item_equal_items_hash_equally() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        fn hash_of(item: &Item) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            item.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        let item = Item::new("item".to_string(), 1, 0);
+        let same = Item::new("item".to_string(), 1, 0);
+        assert!(item.equals(&same).unwrap());
+        assert_eq!(hash_of(&item), hash_of(&same));
+
+        let mut set = std::collections::HashSet::new();
+        set.insert(item.clone());
+        assert!(set.contains(&same));
+        assert!(!set.contains(&Item::new("item".to_string(), 1, 1)));
+    }
+
+    #[test]
+    fn test_object_attribute_access() {
+        let object = Object::new("Tom::Test::Object".to_string(), "test object".to_string())
+            .with_attr("count", CdcValue::INTEGER(3))
+            .with_attr("name", CdcValue::STRING("first".to_string()));
+
+        assert_eq!(object.attr("count"), Some(&CdcValue::INTEGER(3)));
+        assert_eq!(object.attr("missing"), None);
+        assert_eq!(object.attr_as::<i64>("count"), Some(3));
+        assert_eq!(object.attr_as::<String>("name"), Some("first".to_string()));
+        assert_eq!(object.attr_as::<String>("count"), None);
+
+        let mut names: Vec<&str> = object.attrs().map(|(name, _)| name).collect();
+        names.sort();
+        assert_eq!(names, vec!["count", "name"]);
+    }
+
+    #[test]
+    fn test_package_metadata_access() {
+        let package = Package::new("package_ref".to_string())
+            .with_metadata("version", CdcValue::INTEGER(2))
+            .with_metadata("selected", CdcValue::BOOL(true));
+
+        assert_eq!(package.reference, "package_ref");
+        assert_eq!(package.metadata_get("version"), Some(&CdcValue::INTEGER(2)));
+        assert_eq!(package.metadata_get("missing"), None);
+        assert_eq!(package.metadata_get_as::<bool>("selected"), Some(true));
+        assert_eq!(package.metadata_get_as::<f64>("version"), None);
+    }
+
+    #[cfg(feature = "std")]
+    /// Installs a connection to `url` as the GOM connection of the current thread
+    fn connect_test_server(url: &str) {
+        install_gom_connection(Connection::init(url, String::new()).unwrap());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_execution_context_params() {
+        connect_test_server(&network::test_server::replying(1, |_, params| {
+            let mut keys: Vec<String> = params.keys().cloned().collect();
+            keys.sort();
+            Ok(CdcValue::LIST(keys.into_iter().map(CdcValue::STRING).collect()))
+        }));
+        let keys = |value: CdcValue| value.expect_list().into_iter().map(CdcValue::expect_string).collect::<Vec<_>>();
+        assert_eq!(keys(execute_command("cmd", vec![], HashMap::new()).unwrap()), ["args", "command", "kwargs"]);
+        let context = ExecutionContext { stage: Some(Stage::Index(2)), selection: vec![Item::new("item".to_string(), 0, CURRENT_STAGE)], ..ExecutionContext::default() };
+        assert_eq!(
+            keys(execute_command_in("cmd", vec![], HashMap::new(), &context).unwrap()),
+            ["args", "command", "kwargs", "selection", "stage"]
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_execution_context_selection() {
+        connect_test_server(&network::test_server::replying(1, |_, params| Ok(params["selection"].clone())));
+        set_default_stage(Stage::Index(3)).unwrap();
+        let context = ExecutionContext { selection: vec![Item::new("item".to_string(), 2, CURRENT_STAGE)], ..ExecutionContext::default() };
+        let selection = execute_command_in("cmd", vec![], HashMap::new(), &context).unwrap().expect_list();
+        let item = selection[0].clone().expect_map();
+        assert_eq!(item["id"], CdcValue::STRING("item".to_string()));
+        assert_eq!(item["category"], CdcValue::INTEGER(2));
+        assert_eq!(item["stage"], CdcValue::INTEGER(3));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_connection_handle() {
+        let url = network::test_server::replying(1, |command, params| match command {
+            command if command == network::Request::TRANSLATE as i64 => {
+                Ok(CdcValue::MAP(HashMap::from([("translation".to_string(), CdcValue::STRING("translated".to_string()))])))
+            }
+            command if command == network::Request::QUERY as i64 => Ok(CdcValue::ITEM(Item::new("queried".to_string(), 0, 0))),
+            command if command == network::Request::GETATTR as i64 => Ok(params["name"].clone()),
+            _ => Ok(CdcValue::LIST(vec![CdcValue::ITEM(Item::new(params["command"].clone().expect_string(), 0, 0))])),
+        });
+        let shared: SharedConnection = Arc::new(Mutex::new(Connection::init(&url, String::new()).unwrap()));
+        let handle = ConnectionHandle::Explicit(shared.clone());
+        let context = ExecutionContext { connection: handle.clone(), ..ExecutionContext::default() };
+        assert_eq!(execute_command_items_in("cmd", vec![], HashMap::new(), &context).unwrap(), vec![Item::new("cmd".to_string(), 0, 0)]);
+        assert_eq!(tr_in("text", None, &handle), "translated");
+        assert_eq!(resolve_item_in("gom.app.project.inspection['Point 1']", &handle).unwrap().id, "queried");
+        // The current thread has no connection of its own
+        assert!(matches!(execute_command("cmd", vec![], HashMap::new()), Err(ConnectionError::NotConnected)));
+        assert_eq!(tr("text", None), "text");
+        assert_eq!(context.connection, ConnectionHandle::Explicit(shared));
+        assert_ne!(context.connection, ConnectionHandle::Global);
+
+        // Item methods and free functions use the entered connection until `enter` returns
+        let item = Item::new("item".to_string(), 0, 0);
+        assert_eq!(handle.enter(|| item.get_attr("name")).unwrap(), CdcValue::STRING("name".to_string()));
+        assert_eq!(handle.enter(|| ConnectionHandle::Global.enter(|| tr("text", None))), "translated");
+        assert!(matches!(item.get_attr("name"), Err(ConnectionError::NotConnected)));
+        let _ = std::panic::catch_unwind(|| handle.enter(|| panic!("failed script")));
+        assert!(matches!(item.get_attr("name"), Err(ConnectionError::NotConnected)));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_execute_command_items() {
+        connect_test_server(&network::test_server::replying(1, |_, params| match params["command"].clone().expect_string().as_str() {
+            "import" => Ok(CdcValue::LIST(vec![
+                CdcValue::ITEM(Item::new("first".to_string(), 1, 0)),
+                CdcValue::MAP(HashMap::from([("id".to_string(), CdcValue::STRING("second".to_string()))])),
+            ])),
+            "mixed" => Ok(CdcValue::LIST(vec![CdcValue::ITEM(Item::new("first".to_string(), 1, 0)), CdcValue::NONE])),
+            _ => Ok(CdcValue::NONE),
+        }));
+        assert_eq!(
+            execute_command_items("import", vec![], CdcDict::new()).unwrap(),
+            vec![Item::new("first".to_string(), 1, 0), Item::new("second".to_string(), 0, CURRENT_STAGE)]
+        );
+        assert!(matches!(
+            execute_command_items("mixed", vec![], CdcDict::new()),
+            Err(ConnectionError::UnexpectedReply(UnexpectedReply { expected_type: CdcType::ITEM, received_type: CdcType::NONE }))
+        ));
+        assert!(matches!(
+            execute_command_items("other", vec![], CdcDict::new()),
+            Err(ConnectionError::UnexpectedReply(UnexpectedReply { expected_type: CdcType::LIST, .. }))
+        ));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_item_exists() {
+        connect_test_server(&network::test_server::replying(1, |_, params| {
+            match params["item"].clone().expect_map()["id"].clone().expect_string().as_str() {
+                "known" => Ok(CdcValue::STRING("known".to_string())),
+                _ => Err(network::connection::error::INDEX),
+            }
+        }));
+        assert!(Item::new("known".to_string(), 0, 0).exists().unwrap());
+        assert!(!Item::new("unknown".to_string(), 0, 0).exists().unwrap());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_gom_session() {
+        let url = network::test_server::replying(1, |command, params| {
+            match params.get("expression").or(params.get("command")) {
+                Some(CdcValue::STRING(text)) => Ok(CdcValue::LIST(vec![CdcValue::INTEGER(command), CdcValue::STRING(text.clone())])),
+                _ => Ok(CdcValue::NONE),
+            }
+        });
+        let session = GomSession::connect(&format!("{url}/?apikey=key&interpreter_id=session")).unwrap();
+        assert_eq!(session.interpreter_id().unwrap(), "session");
+        assert_eq!(
+            session.command("cmd", vec![], HashMap::new()).unwrap(),
+            CdcValue::LIST(vec![CdcValue::INTEGER(network::Request::COMMAND as i64), CdcValue::STRING("cmd".to_string())])
+        );
+        assert_eq!(
+            session.query("gom.app.project").unwrap(),
+            CdcValue::LIST(vec![CdcValue::INTEGER(network::Request::QUERY as i64), CdcValue::STRING("gom.app.project".to_string())])
+        );
+        assert_eq!(session.item("element").id, "element");
+        // The session is not the connection of the thread unless entered or installed
+        assert!(matches!(execute_command("cmd", vec![], HashMap::new()), Err(ConnectionError::NotConnected)));
+        assert_eq!(
+            session.enter(|| execute_command("cmd", vec![], HashMap::new())).unwrap(),
+            CdcValue::LIST(vec![CdcValue::INTEGER(network::Request::COMMAND as i64), CdcValue::STRING("cmd".to_string())])
+        );
+        assert!(matches!(execute_command("cmd", vec![], HashMap::new()), Err(ConnectionError::NotConnected)));
+        drop(session);
+        assert!(matches!(GomSession::connect("not a url"), Err(ConnectionError::Connect(_))));
+        let url = network::test_server::replying(1, |_, _| Ok(CdcValue::NONE));
+        let session = GomSession::connect_with_interpreter_id(&format!("{url}/?apikey=key&interpreter_id=session"), "inspection report").unwrap();
+        assert_eq!(session.interpreter_id().unwrap(), "inspection report");
+        drop(session);
+        let url = network::test_server::replying(1, |_, _| Ok(CdcValue::NONE));
+        let options = ConnectOptions { interpreter_id: Some("measurement".to_string()), ..ConnectOptions::default() };
+        let session = GomSession::connect_with(&format!("{url}/?apikey=key&interpreter_id=session"), options).unwrap();
+        assert_eq!(session.interpreter_id().unwrap(), "measurement");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_gom_session_drop() {
+        static RELEASED: AtomicBool = AtomicBool::new(false);
+        let url = network::test_server::replying(1, |command, _| {
+            if command == network::Request::RELEASE as i64 {
+                RELEASED.store(true, std::sync::atomic::Ordering::SeqCst);
+            }
+            Ok(CdcValue::NONE)
+        });
+        connect_test_server(&network::test_server::replying(1, |_, _| Ok(CdcValue::STRING("thread".to_string()))));
+        drop(GomSession::connect(&format!("{url}/?apikey=key&interpreter_id=session")).unwrap());
+        assert!(RELEASED.load(std::sync::atomic::Ordering::SeqCst));
+        // The connection of the thread is not the session's to close
+        assert_eq!(execute_command("cmd", vec![], HashMap::new()).unwrap(), CdcValue::STRING("thread".to_string()));
+
+        let url = network::test_server::replying(1, |_, _| Ok(CdcValue::STRING("session".to_string())));
+        let session = GomSession::connect(&format!("{url}/?apikey=key&interpreter_id=session")).unwrap();
+        let handle = session.connection();
+        let session = session.install().unwrap_err();
+        drop(handle);
+        session.install().unwrap();
+        assert_eq!(execute_command("cmd", vec![], HashMap::new()).unwrap(), CdcValue::STRING("session".to_string()));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_default_kwargs() {
+        connect_test_server(&network::test_server::replying(1, |_, params| Ok(params["kwargs"].clone())));
+        set_default_kwargs(HashMap::from([
+            ("units".to_string(), CdcValue::STRING("mm".to_string())),
+            ("tenant".to_string(), CdcValue::INTEGER(1)),
+        ]))
+        .unwrap();
+        let kwargs = execute_command("cmd", vec![], HashMap::from([("units".to_string(), CdcValue::STRING("inch".to_string()))])).unwrap();
+        assert_eq!(
+            kwargs,
+            CdcValue::MAP(HashMap::from([
+                ("units".to_string(), CdcValue::STRING("inch".to_string())),
+                ("tenant".to_string(), CdcValue::INTEGER(1)),
+            ]))
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_item_filter_items() {
+        connect_test_server(&network::test_server::replying(1, |_, params| match params["expression"].clone().expect_string().as_str() {
+            "items" => Ok(CdcValue::LIST(vec![
+                CdcValue::ITEM(Item::new("first".to_string(), 1, 0)),
+                CdcValue::MAP(HashMap::from([("id".to_string(), CdcValue::STRING("second".to_string()))])),
+            ])),
+            _ => Ok(CdcValue::LIST(vec![CdcValue::INTEGER(1)])),
+        }));
+        let item = Item::new("parent".to_string(), 0, 0);
+        assert_eq!(
+            item.filter_items("items", None).unwrap(),
+            vec![Item::new("first".to_string(), 1, 0), Item::new("second".to_string(), 0, CURRENT_STAGE)]
+        );
+        assert!(matches!(
+            item.filter_items("values", None),
+            Err(ConnectionError::UnexpectedReply(UnexpectedReply { expected_type: CdcType::ITEM, received_type: CdcType::INTEGER }))
+        ));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_item_index_and_key() {
+        connect_test_server(&network::test_server::replying(1, |command, params| match command {
+            command if command == network::Request::INDEX as i64 => Ok(params["index"].clone()),
+            command if command == network::Request::KEY as i64 => Ok(params["name"].clone()),
+            _ => Err("RuntimeError"),
+        }));
+        let item = Item::new("elements".to_string(), 0, 0);
+        assert_eq!(item.get_index(-1).unwrap(), CdcValue::INTEGER(-1));
+        assert_eq!(item.get_item("key").unwrap(), CdcValue::STRING("key".to_string()));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_set_attr_returning() {
+        connect_test_server(&network::test_server::replying(1, |_, params| match &params["value"] {
+            CdcValue::FLOAT(mm) => Ok(CdcValue::FLOAT(mm / 1000.0)),
+            _ => Ok(CdcValue::NONE),
+        }));
+        let item = Item::new("element".to_string(), 0, 0);
+        assert_eq!(item.set_attr_returning("offset", CdcValue::FLOAT(1500.0)).unwrap(), CdcValue::FLOAT(1.5));
+        assert_eq!(item.set_attr_returning("name", CdcValue::STRING("name".to_string())).unwrap(), CdcValue::NONE);
+        assert!(item.set_attr("offset", CdcValue::FLOAT(1.0)).is_ok());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_type_name() {
+        connect_test_server(&network::test_server::replying(1, |_, params| match params["name"].clone().expect_string().as_str() {
+            "type" => match params["item"].clone().expect_map()["id"].clone().expect_string().as_str() {
+                "point" => Ok(CdcValue::STRING("inspection.point".to_string())),
+                _ => Ok(CdcValue::NONE),
+            },
+            _ => Err("AttributeError"),
+        }));
+        assert_eq!(Item::new("point".to_string(), 0, 0).type_name().unwrap(), "inspection.point");
+        assert!(matches!(
+            Item::new("other".to_string(), 0, 0).type_name(),
+            Err(ConnectionError::UnexpectedReply(UnexpectedReply { expected_type: CdcType::STRING, received_type: CdcType::NONE }))
+        ));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_typed_attrs() {
+        connect_test_server(&network::test_server::replying(1, |_, params| match params.get("name").cloned().map(CdcValue::expect_string).as_deref() {
+            Some("visible") => Ok(CdcValue::BOOL(true)),
+            Some("count") => Ok(CdcValue::INTEGER(3)),
+            Some("deviation") => Ok(CdcValue::FLOAT(0.25)),
+            Some("name") => Ok(CdcValue::STRING("Point 1".to_string())),
+            Some(_) => Err(network::connection::error::ATTRIBUTE),
+            // The REPR request checking that the item still exists
+            None => Ok(CdcValue::STRING("point".to_string())),
+        }));
+        let item = Item::new("point".to_string(), 0, 0);
+        assert!(item.get_bool("visible").unwrap());
+        assert_eq!(item.get_int("count").unwrap(), 3);
+        assert_eq!(item.get_float("deviation").unwrap(), 0.25);
+        assert_eq!(item.get_string("name").unwrap(), "Point 1");
+        assert!(matches!(
+            item.get_bool("count"),
+            Err(ConnectionError::UnexpectedReply(UnexpectedReply { expected_type: CdcType::BOOLEAN, received_type: CdcType::INTEGER }))
+        ));
+        assert!(matches!(item.get_float("missing"), Err(ConnectionError::Attribute(_))));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_tr_checked() {
+        connect_test_server(&network::test_server::replying(1, |_, params| match params["text"].clone().expect_string().as_str() {
+            "known" => Ok(CdcValue::MAP(HashMap::from([("translation".to_string(), CdcValue::STRING("bekannt".to_string()))]))),
+            "unknown" => Ok(CdcValue::MAP(HashMap::from([("translation".to_string(), CdcValue::NONE)]))),
+            _ => Ok(CdcValue::STRING("malformed".to_string())),
+        }));
+        assert_eq!(tr_checked("known", None).unwrap(), "bekannt");
+        assert_eq!(tr_checked("unknown", None).unwrap(), "unknown");
+        assert!(matches!(tr_checked("broken", None), Err(ConnectionError::UnexpectedReply(_))));
+        assert_eq!(tr("broken", None), "broken");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_resolve_item() {
+        connect_test_server(&network::test_server::replying(1, |_, params| match params["expression"].clone().expect_string().as_str() {
+            "gom.app.project.elements['Surface 1']" => Ok(CdcValue::ITEM(Item::new("surface".to_string(), 3, 0))),
+            _ => Ok(CdcValue::INTEGER(1)),
+        }));
+        assert_eq!(resolve_item("gom.app.project").unwrap(), Item::new("gom.app.project".to_string(), 0, CURRENT_STAGE));
+        assert_eq!(resolve_item("gom.app.project.elements['Surface 1']").unwrap(), Item::new("surface".to_string(), 3, 0));
+        assert!(matches!(resolve_item("gom.app.project.name.upper()"), Err(ConnectionError::UnexpectedReply(_))));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_item_data_attr() {
+        connect_test_server(&network::test_server::replying(1, |command, params| match command {
+            command if command == network::Request::DATA_ATTR as i64 => Ok(params["stage"].clone()),
+            _ => Err("AttributeError"),
+        }));
+        let item = Item::new("element".to_string(), 0, 2);
+        assert_eq!(item.data_attr("result_dimension.deviation", None).unwrap(), CdcValue::INTEGER(2));
+        assert_eq!(item.data_attr("result_dimension.deviation", Some(Stage::Index(5))).unwrap(), CdcValue::INTEGER(5));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_missing_and_empty_params_differ() {
+        connect_test_server(&network::test_server::replying(1, |command, params| {
+            let sent = match command {
+                command if command == network::Request::TRANSLATE as i64 => params.get("id"),
+                _ => params.get("index"),
+            };
+            let translation = match sent {
+                None => "absent".to_string(),
+                Some(CdcValue::NONE) => "none".to_string(),
+                Some(value) => format!("{:?}", value),
+            };
+            Ok(CdcValue::MAP(HashMap::from([("translation".to_string(), CdcValue::STRING(translation))])))
+        }));
+        assert_eq!(tr("text", None), "absent");
+        assert_eq!(tr("text", Some("")), "STRING(\"\")");
+
+        let item = Item::new("item".to_string(), 0, 0);
+        let index_param = |index| item.get("name", index).unwrap().expect_map().remove("translation").unwrap().expect_string();
+        assert_eq!(index_param(None), "absent");
+        assert_eq!(index_param(Some(0)), "INTEGER(0)");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_translation_cache() {
+        // Each request is answered with a different translation, so cached answers are recognizable
+        connect_test_server(&network::test_server::replying(1, |_, _| {
+            static COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+            let count = COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(CdcValue::MAP(HashMap::from([("translation".to_string(), CdcValue::STRING(count.to_string()))])))
+        }));
+        assert_eq!(tr("label", None), "0");
+        assert_eq!(tr("label", None), "1");
+        set_translation_cache_capacity(8).unwrap();
+        assert_eq!(tr("label", None), "2");
+        assert_eq!(tr("label", None), "2");
+        assert_eq!(tr("label", Some("id")), "3");
+        clear_translation_cache();
+        assert_eq!(tr("label", None), "4");
+    }
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(Slice::default(), Slice { start: None, stop: None });
+        assert_eq!(Trait::default(), Trait { id: String::new(), args: vec![], kwargs: HashMap::new() });
+        assert_eq!(Item::default(), Item::new(String::new(), 0, CURRENT_STAGE));
+        #[cfg(feature = "std")]
+        {
+            let config = ConnectionConfig::default();
+            assert!(config.api_key.is_empty());
+            assert!(config.strip_tracebacks);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_item_get_attrs() {
+        connect_test_server(&network::test_server::replying(2, |_, params| {
+            Ok(CdcValue::STRING(format!("value of {}", params["name"].clone().expect_string())))
+        }));
+        let item = Item::new("item".to_string(), 1, 0);
+        let attrs = item.get_attrs(&["first", "second"]).unwrap();
+        assert_eq!(attrs.len(), 2);
+        assert_eq!(attrs["first"], CdcValue::STRING("value of first".to_string()));
+        assert_eq!(attrs["second"], CdcValue::STRING("value of second".to_string()));
+    }
+
+    #[test]
+    fn test_stage_conversion() {
+        assert_eq!(i64::from(Stage::Current), -1);
+        assert_eq!(Stage::try_from(-1i64).unwrap(), Stage::Current);
+        assert_eq!(Stage::try_from(1i64).unwrap(), Stage::Index(1));
+        assert_eq!(Stage::try_from(-2i64), Err(InvalidStage(-2)));
+        assert_eq!(Stage::try_from(i64::from(u32::MAX) + 1), Err(InvalidStage(i64::from(u32::MAX) + 1)));
+        for stage in [Stage::Current, Stage::Index(0), Stage::Index(1)] {
+            assert_eq!(Stage::try_from(i64::from(stage)).unwrap(), stage);
+            assert_eq!(Stage::try_from(i32::try_from(stage).unwrap()).unwrap(), stage);
+        }
+
+        let item = Item::new("item".to_string(), 0, CURRENT_STAGE);
+        assert_eq!(item.stage_enum(), Some(Stage::Current));
+        let item = item.in_stage(Stage::Index(1)).unwrap();
+        assert_eq!((item.stage, item.stage_enum()), (1, Some(Stage::Index(1))));
+        assert_eq!(item.in_stage(Stage::Index(u32::MAX)), Err(InvalidStage(i64::from(u32::MAX))));
+    }
+
+    #[test]
+    fn test_item_as_reference() {
+        let item = Item::new("item".to_string(), 5, 2);
+        let reference = item.as_reference().expect_map();
+        assert_eq!(reference["$type"], CdcValue::STRING("reference".to_string()));
+        assert_eq!(reference["id"], CdcValue::STRING("item".to_string()));
+        assert_eq!(reference["category"], CdcValue::INTEGER(5));
+        assert_eq!(reference, item.to_api_json());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_not_connected_error() {
+        let result = execute_command("gom.script.sys.test", vec![], CdcDict::new());
+        assert!(matches!(result, Err(ConnectionError::NotConnected)));
+
+        let item = Item::new("item".to_string(), 0, 0);
+        assert!(matches!(item.get_attr("name"), Err(ConnectionError::NotConnected)));
+        assert!(matches!(set_default_stage(Stage::Index(1)), Err(ConnectionError::NotConnected)));
     }
 
+    #[cfg(feature = "std")]
     #[test]
     fn test_tr_without_connection() {
         // Test that tr returns original text when no connection is available
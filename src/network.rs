@@ -1,11 +1,15 @@
 use tungstenite::{Message, connect, stream::MaybeTlsStream, WebSocket, Error};
-use std::{collections::HashMap, net::TcpStream as TCPStream};
+use std::{collections::{HashMap, HashSet, VecDeque}, net::TcpStream as TCPStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use std::fmt;
 use tungstenite::Bytes;
 use uuid::Uuid;
 use crate::encoding::{self as enc, CdcEncoder};
 
 
 #[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Request{
     API = 1,
     COMMAND = 2,
@@ -65,6 +69,34 @@ pub enum Request{
     TEST_5 = 1005,
 
 }
+
+/// The test requests a server answers for protocol conformance testing, see `Connection::run_protocol_test`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TestCase {
+    Test,
+    Test0,
+    Test1,
+    Test2,
+    Test3,
+    Test4,
+    Test5,
+}
+impl TestCase {
+    pub const ALL: [TestCase; 7] = [TestCase::Test, TestCase::Test0, TestCase::Test1, TestCase::Test2, TestCase::Test3, TestCase::Test4, TestCase::Test5];
+
+    /// Returns the request code sent for this test case
+    pub fn request(self) -> Request {
+        match self {
+            TestCase::Test => Request::TEST,
+            TestCase::Test0 => Request::TEST_0,
+            TestCase::Test1 => Request::TEST_1,
+            TestCase::Test2 => Request::TEST_2,
+            TestCase::Test3 => Request::TEST_3,
+            TestCase::Test4 => Request::TEST_4,
+            TestCase::Test5 => Request::TEST_5,
+        }
+    }
+}
 pub mod connection{
     pub mod error{
         pub const ABORT: &str = "Tom::GScript::BreakException";
@@ -91,6 +123,17 @@ pub mod connection{
             ERROR(Error),
             REPLY(CdcValue),
         }
+
+        impl Reply{
+            /// Converts the reply into the result of a request, turning error values into errors
+            pub(crate) fn into_result(self) -> Result<CdcValue, crate::network::ConnectionError>{
+                match self{
+                    Reply::ERROR(err) => Err(err.into()),
+                    Reply::REPLY(CdcValue::ERROR(err)) => Err(crate::network::ConnectionError::CdcError(err)),
+                    Reply::REPLY(value) => Ok(value),
+                }
+            }
+        }
     }
     
     pub mod attribute{
@@ -117,116 +160,2378 @@ pub mod connection{
         }
     }
 }
-struct UnexcpectedReply{
-    expected_type: enc::CdcType,    
-    received_type: enc::CdcType,
+/// The outer map of a message from the server, parsed into its fields.
+///
+/// The fields that depend on the message type are parsed into `kind`.
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    /// The message type, one of `connection::attribute::types`
+    pub msg_type: String,
+    /// The id of the request the message belongs to
+    pub id: Option<String>,
+    pub params: Option<enc::CdcDict>,
+    pub kind: EnvelopeKind,
+}
+
+/// The content of a message, by message type
+#[derive(Debug, Clone)]
+pub enum EnvelopeKind {
+    /// The value of a `REPLY`, or of a `RESULT` of a `RUNAPI` request
+    Reply(enc::CdcValue),
+    Error(EnvelopeError),
+    /// The server calls a function of this client
+    Call { function: enc::CdcCallable, args: enc::CdcList, kwargs: enc::CdcDict },
+    Wait,
+    /// A message type this client doesn't know, with its value if it has one
+    Other(Option<enc::CdcValue>),
+}
+
+/// The exception of an error message
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnvelopeError {
+    pub error_type: String,
+    pub description: String,
+    pub code: i64,
+    pub log: String,
+    /// The encoded exception value
+    pub value: Vec<u8>,
+}
+
+impl Envelope {
+    /// Decodes a message that has to span the whole buffer.
+    ///
+    /// Calls refer to callables registered in the encoder that sent them, so decode those with
+    /// the encoder and parse the value with `from_value`.
+    pub fn decode(buffer: &[u8]) -> Result<Envelope, enc::DecodeError> {
+        Envelope::from_value(enc::decode_complete(buffer)?)
+    }
+    /// Parses a decoded message, failing with `DecodeError::InvalidEnvelope` if a key required by
+    /// the message type is missing or has the wrong type
+    pub fn from_value(value: enc::CdcValue) -> Result<Envelope, enc::DecodeError> {
+        use connection::attribute;
+        let Ok(mut map) = enc::CdcDict::try_from(value) else {
+            return Err(enc::DecodeError::InvalidEnvelope { key: attribute::TYPE });
+        };
+        let msg_type: String = Envelope::require(&mut map, attribute::TYPE)?;
+        let kind = match &msg_type[..] {
+            attribute::types::ERROR => EnvelopeKind::Error(EnvelopeError {
+                error_type: Envelope::require(&mut map, attribute::ERROR)?,
+                description: Envelope::require(&mut map, attribute::DESCRIPTION)?,
+                code: Envelope::require(&mut map, attribute::CODE)?,
+                log: Envelope::require(&mut map, attribute::LOG)?,
+                value: Envelope::require(&mut map, attribute::VALUE)?,
+            }),
+            attribute::types::REPLY | attribute::types::RESULT => {
+                EnvelopeKind::Reply(map.remove(attribute::VALUE).ok_or(enc::DecodeError::InvalidEnvelope { key: attribute::VALUE })?)
+            }
+            attribute::types::CALL => EnvelopeKind::Call {
+                function: match map.remove(attribute::VALUE) {
+                    Some(enc::CdcValue::CALLABLE(function)) => function,
+                    _ => return Err(enc::DecodeError::InvalidEnvelope { key: attribute::VALUE }),
+                },
+                args: Envelope::require(&mut map, attribute::ARGS)?,
+                kwargs: Envelope::require(&mut map, attribute::KWARGS)?,
+            },
+            attribute::types::WAIT => EnvelopeKind::Wait,
+            _ => EnvelopeKind::Other(map.remove(attribute::VALUE)),
+        };
+        Ok(Envelope {
+            // Messages without a usable id go to the request that is waited for
+            id: match map.remove(attribute::ID) {
+                Some(enc::CdcValue::STRING(id)) => Some(id),
+                _ => None,
+            },
+            params: Envelope::take(&mut map, attribute::PARAMS)?,
+            kind,
+            msg_type,
+        })
+    }
+    fn take<T: TryFrom<enc::CdcValue, Error = enc::CdcValue>>(map: &mut enc::CdcDict, key: &'static str) -> Result<Option<T>, enc::DecodeError> {
+        map.remove(key)
+            .map(|value| T::try_from(value).map_err(|_| enc::DecodeError::InvalidEnvelope { key }))
+            .transpose()
+    }
+    fn require<T: TryFrom<enc::CdcValue, Error = enc::CdcValue>>(map: &mut enc::CdcDict, key: &'static str) -> Result<T, enc::DecodeError> {
+        Envelope::take(map, key)?.ok_or(enc::DecodeError::InvalidEnvelope { key })
+    }
+}
+
+/// Conversion of a value into the parameter map of a request.
+///
+/// Implement this for custom parameter structs to pass them to `Connection::request` directly
+/// instead of assembling a map at every call site.
+pub trait ToParams {
+    fn to_params(&self) -> enc::CdcDict;
+
+    /// Converts into the parameter map, avoiding clones where the implementation allows it
+    fn into_params(self) -> enc::CdcDict where Self: Sized {
+        self.to_params()
+    }
+}
+
+impl ToParams for enc::CdcDict {
+    fn to_params(&self) -> enc::CdcDict {
+        self.clone()
+    }
+    fn into_params(self) -> enc::CdcDict {
+        self
+    }
+}
+
+impl ToParams for () {
+    fn to_params(&self) -> enc::CdcDict {
+        HashMap::new()
+    }
+}
+
+impl<K: AsRef<str>> ToParams for [(K, enc::CdcValue)] {
+    fn to_params(&self) -> enc::CdcDict {
+        self.iter().map(|(key, value)| (key.as_ref().to_string(), value.clone())).collect()
+    }
+}
+
+impl<K: AsRef<str>, const N: usize> ToParams for [(K, enc::CdcValue); N] {
+    fn to_params(&self) -> enc::CdcDict {
+        self.as_slice().to_params()
+    }
+    fn into_params(self) -> enc::CdcDict {
+        self.into_iter().map(|(key, value)| (key.as_ref().to_string(), value)).collect()
+    }
+}
+
+impl<K: AsRef<str>> ToParams for Vec<(K, enc::CdcValue)> {
+    fn to_params(&self) -> enc::CdcDict {
+        self.as_slice().to_params()
+    }
+    fn into_params(self) -> enc::CdcDict {
+        self.into_iter().map(|(key, value)| (key.as_ref().to_string(), value)).collect()
+    }
+}
+
+impl<T: ToParams + ?Sized> ToParams for &T {
+    fn to_params(&self) -> enc::CdcDict {
+        (**self).to_params()
+    }
+}
+
+/// Controls at which level the requests of a `Connection` are logged.
+///
+/// Every request kind is logged at `default_level` unless an override is set for it.
+/// Request and reply payloads are logged at `trace` level, truncated to `max_payload_len` characters.
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    /// Level used for request kinds without an override
+    pub default_level: log::LevelFilter,
+    /// Per request kind overrides
+    pub levels: HashMap<Request, log::LevelFilter>,
+    /// Maximum number of characters of a payload written to the log
+    pub max_payload_len: usize,
+}
+
+impl LogConfig {
+    pub fn new() -> Self {
+        LogConfig {
+            default_level: log::LevelFilter::Debug,
+            levels: HashMap::new(),
+            max_payload_len: 256,
+        }
+    }
+
+    /// Sets the level used for the given request kind
+    pub fn with_level(mut self, request: Request, level: log::LevelFilter) -> Self {
+        self.levels.insert(request, level);
+        self
+    }
+
+    /// Returns the level the given request kind is logged at
+    pub fn level_for(&self, request: Request) -> log::LevelFilter {
+        self.levels.get(&request).copied().unwrap_or(self.default_level)
+    }
+
+    /// Formats a payload for the log, truncating it to `max_payload_len` characters
+    pub fn format_payload(&self, value: &enc::CdcValue) -> String {
+        let text = format!("{:?}", value);
+        match text.char_indices().nth(self.max_payload_len) {
+            Some((cut, _)) => format!("{}... ({} bytes truncated)", &text[..cut], text.len() - cut),
+            None => text,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The types of a reply that differs from the type the request yields
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnexpectedReply{
+    pub expected_type: enc::CdcType,
+    pub received_type: enc::CdcType,
+}
+impl UnexpectedReply {
+    /// Returns the error for receiving `received` instead of a value of `expected_type`
+    pub(crate) fn error(expected_type: enc::CdcType, received: &enc::CdcValue) -> ConnectionError {
+        ConnectionError::UnexpectedReply(UnexpectedReply { expected_type, received_type: enc::CdcType::from(received) })
+    }
+}
+/// Details of an error the server raised while processing a request
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerError {
+    /// The exception type, e.g. `connection::error::PYTHON`
+    pub error_type: String,
+    pub description: String,
+    pub code: i64,
+    /// The server log of the failure, usually a traceback
+    pub log: String,
+    /// The file and line last reported with `Connection::set_line` before the request
+    pub line: Option<(String, i64)>,
 }
+impl ServerError {
+    fn at(self, file: &str, line: i64) -> Self {
+        ServerError { line: Some((file.to_string(), line)), ..self }
+    }
+}
+impl From<connection::reply::Error> for ServerError {
+    fn from(err: connection::reply::Error) -> Self {
+        ServerError { error_type: err.error_type, description: err.description, code: err.code, log: err.log, line: None }
+    }
+}
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (code {}): {}", self.error_type, self.code, self.description)?;
+        if let Some((file, line)) = &self.line {
+            write!(f, " at {}:{}", file, line)?;
+        }
+        if !self.log.is_empty() {
+            write!(f, "\n{}", self.log)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub enum ConnectionError{
-    Attribute,
-    Import,
-    Index,
+    /// No connection has been initialized
+    NotConnected,
+    Attribute(ServerError),
+    Import(ServerError),
+    Index(ServerError),
+    /// The reply did not have the type or content expected for the request
     Request,
-    Break,
+    Break(ServerError),
+    /// The server raised an exception without a dedicated variant, e.g. a Python exception
+    Server(ServerError),
+    /// No reply arrived before the timeout expired
+    Timeout,
+    /// The request was cancelled through its cancel flag
+    Cancelled,
+    /// The server replied with an error value, or a message could not be encoded or decoded
+    CdcError(crate::CdcError),
+    /// A message, e.g. the result of a callback, could not be sent to the server
+    SendFailed,
+    /// The request referenced an item the server no longer knows, e.g. one obtained in an
+    /// earlier session. The item has to be queried again; retrying the request will not help.
+    StaleItem,
+    /// The connection to the server could not be established, with the reason
+    Connect(String),
+    /// The reply arrived but was evicted because too many replies were buffered, see
+    /// `Connection::set_max_buffered_replies`
+    ReplyDropped,
+    /// The reply had a different type than the request yields, e.g. a STRING for `Item::len`
+    UnexpectedReply(UnexpectedReply),
+    /// The server closed the connection or reading from the socket failed, with the reason
+    ConnectionClosed(String),
+}
+impl From<enc::EncodeError> for ConnectionError {
+    fn from(err: enc::EncodeError) -> Self {
+        ConnectionError::CdcError(crate::CdcError { id: "EncodeError".to_string(), text: err.to_string(), line: 0 })
+    }
+}
+impl From<enc::DecodeError> for ConnectionError {
+    fn from(err: enc::DecodeError) -> Self {
+        ConnectionError::CdcError(crate::CdcError { id: "DecodeError".to_string(), text: err.to_string(), line: 0 })
+    }
 }
 impl From<connection::reply::Error> for ConnectionError{
     fn from(err: connection::reply::Error) -> Self {
-        match err.error_type.as_str(){
-            connection::error::ABORT => ConnectionError::Break,
-            connection::error::ATTRIBUTE => ConnectionError::Attribute,
-            connection::error::IMPORT => ConnectionError::Import,
-            connection::error::INDEX => ConnectionError::Index,
-            _ => ConnectionError::Request
+        let error_type = err.error_type.clone();
+        let err = ServerError::from(err);
+        match error_type.as_str(){
+            connection::error::ABORT => ConnectionError::Break(err),
+            connection::error::ATTRIBUTE => ConnectionError::Attribute(err),
+            connection::error::IMPORT => ConnectionError::Import(err),
+            connection::error::INDEX => ConnectionError::Index(err),
+            _ => ConnectionError::Server(err)
         }
     }
 }
-pub struct Connection {
-    socket: WebSocket<MaybeTlsStream<TCPStream>>,
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::NotConnected => write!(f, "No GOM connection has been initialized"),
+            ConnectionError::Attribute(err) => write!(f, "Attribute error: {}", err),
+            ConnectionError::Import(err) => write!(f, "Import error: {}", err),
+            ConnectionError::Index(err) => write!(f, "Index error: {}", err),
+            ConnectionError::Request => write!(f, "Unexpected reply to the request"),
+            ConnectionError::Break(err) => write!(f, "Execution was aborted: {}", err),
+            ConnectionError::Server(err) => write!(f, "Server error: {}", err),
+            ConnectionError::Timeout => write!(f, "No reply arrived before the timeout expired"),
+            ConnectionError::Cancelled => write!(f, "The request was cancelled"),
+            ConnectionError::CdcError(err) => write!(f, "Error {} in line {}: {}", err.id, err.line, err.text),
+            ConnectionError::SendFailed => write!(f, "A message could not be sent to the server"),
+            ConnectionError::StaleItem => write!(f, "The item is no longer known to the server"),
+            ConnectionError::Connect(reason) => write!(f, "Connecting failed: {}", reason),
+            ConnectionError::ReplyDropped => write!(f, "The reply was dropped from the full reply buffer"),
+            ConnectionError::ConnectionClosed(reason) => write!(f, "The connection was closed: {}", reason),
+            ConnectionError::UnexpectedReply(reply) => write!(f, "Expected a {:?} reply, found {:?}", reply.expected_type, reply.received_type),
+        }
+    }
+}
+/// TLS settings for `wss://` connections, see `Connection::init_with_tls`.
+///
+/// The bundled webpki roots are always trusted; additional roots, e.g. of a private CA
+/// in front of a corporate GOM server, can be added as PEM certificates.
+#[cfg(feature = "tls")]
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    /// Additional trusted root certificates, PEM encoded
+    pub root_certificates_pem: Vec<Vec<u8>>,
+    /// Accept any server certificate without verification.
+    ///
+    /// INSECURE: this makes the connection open to man-in-the-middle attacks.
+    /// Only use it for local development setups with self-signed certificates.
+    pub danger_accept_invalid_certs: bool,
+}
+
+#[cfg(feature = "tls")]
+impl TlsConfig {
+    /// Adds PEM encoded root certificates to trust
+    pub fn with_root_certificate_pem(mut self, pem: &[u8]) -> Self {
+        self.root_certificates_pem.push(pem.to_vec());
+        self
+    }
+
+    /// Adds the PEM encoded root certificates stored in a file to trust
+    pub fn with_root_certificate_file(self, path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let pem = std::fs::read(path)?;
+        Ok(self.with_root_certificate_pem(&pem))
+    }
+
+    /// Builds the rustls client configuration for these settings
+    fn client_config(&self) -> Result<rustls::ClientConfig, Error> {
+        use rustls_pki_types::{CertificateDer, pem::PemObject};
+
+        let provider = std::sync::Arc::new(rustls::crypto::ring::default_provider());
+        let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| Error::Tls(e.into()))?;
+        if self.danger_accept_invalid_certs {
+            log::warn!("TLS certificate verification is disabled, the connection is insecure");
+            return Ok(builder
+                .dangerous()
+                .with_custom_certificate_verifier(std::sync::Arc::new(tls::AcceptAnyCertificate(provider)))
+                .with_no_client_auth());
+        }
+        let mut roots = rustls::RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        for pem in &self.root_certificates_pem {
+            for cert in CertificateDer::pem_slice_iter(pem) {
+                let cert = cert.map_err(|e| Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))?;
+                roots.add(cert).map_err(|e| Error::Tls(e.into()))?;
+            }
+        }
+        Ok(builder.with_root_certificates(roots).with_no_client_auth())
+    }
+}
+
+#[cfg(feature = "tls")]
+mod tls {
+    use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+    use rustls::crypto::CryptoProvider;
+    use rustls::{DigitallySignedStruct, SignatureScheme};
+    use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+    use std::sync::Arc;
+
+    /// Certificate verifier that accepts every server, see `TlsConfig::danger_accept_invalid_certs`
+    #[derive(Debug)]
+    pub(super) struct AcceptAnyCertificate(pub(super) Arc<CryptoProvider>);
+
+    impl ServerCertVerifier for AcceptAnyCertificate {
+        fn verify_server_cert(&self, _end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>], _server_name: &ServerName<'_>, _ocsp_response: &[u8], _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+        fn verify_tls12_signature(&self, _message: &[u8], _cert: &CertificateDer<'_>, _dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+        fn verify_tls13_signature(&self, _message: &[u8], _cert: &CertificateDer<'_>, _dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.0.signature_verification_algorithms.supported_schemes()
+        }
+    }
+}
+
+/// A connection to the server, over a TCP stream unless it was created with `from_socket`
+pub struct Connection<S = MaybeTlsStream<TCPStream>> {
+    socket: WebSocket<S>,
     api_acces_key: String,
     interpreter_id: String,
     replies: HashMap<Uuid, connection::reply::Reply>,
+    /// Ids of `replies` in arrival order; may also hold ids whose reply was consumed since
+    reply_order: VecDeque<Uuid>,
+    max_buffered_replies: usize,
     encoder: enc::CdcEncoder,
+    log_config: LogConfig,
+    /// Item maps of sent requests, reused by `item_map`
+    item_scratch: Vec<enc::CdcDict>,
+    abandoned: HashSet<Uuid>,
+    pending: HashSet<Uuid>,
+    default_stage: crate::Stage,
+    keep_last_reply: bool,
+    last_reply: Option<Bytes>,
+    line: Option<(String, i64)>,
+    default_kwargs: enc::CdcDict,
+    /// Ids of the idempotent requests that have not completed yet, by key
+    idempotent: HashMap<String, Uuid>,
+    translations: TranslationCache,
+    /// Generates the ids of requests, `Uuid::new_v4` by default
+    request_ids: Box<dyn FnMut() -> Uuid + Send>,
+    /// Whether `register` advertises the client capabilities, off by default
+    negotiate_capabilities: bool,
+    server_capabilities: Capabilities,
+}
+
+/// A stream a `Connection` can run on, see `Connection::from_socket`
+pub trait Transport: std::io::Read + std::io::Write {
+    /// Returns the TCP stream underneath, which timeouts and socket options are applied to.
+    ///
+    /// Streams without one, e.g. in-memory pipes in tests, ignore those settings.
+    fn tcp_stream(&self) -> Option<&TCPStream> {
+        None
+    }
+}
+impl Transport for MaybeTlsStream<TCPStream> {
+    fn tcp_stream(&self) -> Option<&TCPStream> {
+        match self {
+            MaybeTlsStream::Plain(stream) => Some(stream),
+            #[cfg(feature = "tls")]
+            MaybeTlsStream::Rustls(stream) => Some(&stream.sock),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the host and port a WebSocket request connects to
+fn host_and_port(request: &tungstenite::handshake::client::Request) -> Result<(String, u16), Error> {
+    let host = request.uri().host().ok_or(Error::Url(tungstenite::error::UrlError::NoHostName))?.to_string();
+    let port = request.uri().port_u16().unwrap_or(match request.uri().scheme_str() {
+        Some("wss") => 443,
+        _ => 80,
+    });
+    Ok((host, port))
+}
+
+/// Settings of the TCP stream underneath a connection, see `Connection::set_socket_config`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SocketConfig {
+    /// Disables Nagle's algorithm. Enabled by default: every request waits for its reply, so
+    /// holding back small frames to coalesce them only adds latency.
+    pub nodelay: bool,
+    /// Size of the kernel receive buffer (`SO_RCVBUF`), `None` keeps the system default
+    pub recv_buffer_size: Option<usize>,
+    /// Size of the kernel send buffer (`SO_SNDBUF`), `None` keeps the system default
+    pub send_buffer_size: Option<usize>,
+}
+impl Default for SocketConfig {
+    fn default() -> Self {
+        SocketConfig { nodelay: true, recv_buffer_size: None, send_buffer_size: None }
+    }
+}
+
+/// Sets a kernel buffer size socket option
+#[cfg(unix)]
+fn set_socket_buffer_size(stream: &TCPStream, option: libc::c_int, size: usize) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let size = libc::c_int::try_from(size).map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+    // SAFETY: the descriptor stays open while `stream` is borrowed, and the option value is a
+    // c_int that lives for the duration of the call
+    let result = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            &size as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 { Ok(()) } else { Err(std::io::Error::last_os_error()) }
 }
 
+/// Number of repeated items in a request above which a warning is logged
+const DUPLICATE_ITEM_WARNING: usize = 1000;
+
+/// Number of item maps a connection keeps for reuse, enough for the two items of a comparison
+const ITEM_SCRATCH_MAPS: usize = 2;
+
+/// Optional protocol features, advertised by the client at registration and answered by the server.
+///
+/// Negotiation is opt-in, see `Connection::set_negotiate_capabilities`. Servers that don't negotiate
+/// support none of them, so optional behaviors have to check `Connection::capabilities` before they
+/// change what is sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Capabilities(u64);
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    /// Maps whose key order is meaningful
+    pub const ORDERED_MAPS: Capabilities = Capabilities(1 << 1);
+
+    /// Returns the capabilities this build of the client supports
+    pub fn client() -> Self {
+        let mut capabilities = Capabilities::NONE;
+        if cfg!(feature = "ordered-maps") {
+            capabilities = capabilities | Capabilities::ORDERED_MAPS;
+        }
+        capabilities
+    }
+    pub fn from_bits(bits: u64) -> Self {
+        Capabilities(bits)
+    }
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+    /// Returns true if all capabilities of `other` are set
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+impl std::ops::BitOr for Capabilities {
+    type Output = Capabilities;
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+impl std::ops::BitAnd for Capabilities {
+    type Output = Capabilities;
+    fn bitand(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+/// Least recently used translations by text and translation id
+#[derive(Debug, Default)]
+struct TranslationCache {
+    capacity: usize,
+    /// Translation and the tick of its last use, by text and id
+    entries: HashMap<TranslationKey, (String, u64)>,
+    /// The keys of `entries` by the tick of their last use, oldest first
+    order: std::collections::BTreeMap<u64, TranslationKey>,
+    tick: u64,
+}
+type TranslationKey = (String, Option<String>);
+impl TranslationCache {
+    fn get(&mut self, text: &str, id: Option<&str>) -> Option<String> {
+        let key = (text.to_string(), id.map(str::to_string));
+        let (translation, last_used) = self.entries.get_mut(&key)?;
+        self.tick += 1;
+        self.order.remove(last_used);
+        *last_used = self.tick;
+        self.order.insert(self.tick, key);
+        Some(translation.clone())
+    }
+    fn insert(&mut self, text: &str, id: Option<&str>, translation: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = (text.to_string(), id.map(str::to_string));
+        self.tick += 1;
+        match self.entries.get_mut(&key) {
+            // Updating a cached text leaves the other entries in place
+            Some((cached, last_used)) => {
+                self.order.remove(last_used);
+                *cached = translation.to_string();
+                *last_used = self.tick;
+            }
+            None => {
+                self.evict((self.entries.len() + 1).saturating_sub(self.capacity));
+                self.entries.insert(key.clone(), (translation.to_string(), self.tick));
+            }
+        }
+        self.order.insert(self.tick, key);
+    }
+    /// Removes the `count` least recently used entries
+    fn evict(&mut self, count: usize) {
+        for _ in 0..count {
+            let Some((_, key)) = self.order.pop_first() else { break };
+            self.entries.remove(&key);
+        }
+    }
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+/// Wraps an error of opening the WebSocket, so the error type of tungstenite stays internal
+fn connect_error(uri: &str, err: Error) -> ConnectionError {
+    match err {
+        Error::Io(e) if matches!(e.kind(), std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock) => {
+            ConnectionError::Connect(format!("{}: timed out", uri))
+        }
+        err => ConnectionError::Connect(format!("{}: {}", uri, err)),
+    }
+}
+
+/// Default of `Connection::set_max_buffered_replies`
+pub const DEFAULT_MAX_BUFFERED_REPLIES: usize = 4096;
+
+/// How often a cancellable request checks its cancel flag while waiting for a reply
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long `Connection::close` waits for the server to release the interpreter and to close the socket each
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
 impl Connection {
-    pub fn init(uri: &str, api_key: String) -> Result<Self, Error> {
-        let (socket, _response) = connect(uri)?;
-        Ok(Self { 
-            socket: socket, 
+    /// Connects to the server at `uri`, failing with `ConnectionError::Connect` naming the URI
+    pub fn init(uri: &str, api_key: String) -> Result<Self, ConnectionError> {
+        let (socket, _response) = connect(uri).map_err(|e| connect_error(uri, e))?;
+        Ok(Self::from_socket(socket, api_key))
+    }
+
+    /// Connects like `init`, using the given TLS settings for `wss://` URIs
+    #[cfg(feature = "tls")]
+    pub fn init_with_tls(uri: &str, api_key: String, tls: &TlsConfig) -> Result<Self, ConnectionError> {
+        Self::open_with_tls(uri, api_key, tls).map_err(|e| connect_error(uri, e))
+    }
+
+    #[cfg(feature = "tls")]
+    fn open_with_tls(uri: &str, api_key: String, tls: &TlsConfig) -> Result<Self, Error> {
+        use tungstenite::client::IntoClientRequest;
+        use tungstenite::handshake::HandshakeError;
+
+        let request = uri.into_client_request()?;
+        let (host, port) = host_and_port(&request)?;
+        let stream = TCPStream::connect((host.as_str(), port))?;
+        let connector = tungstenite::Connector::Rustls(std::sync::Arc::new(tls.client_config()?));
+        let (socket, _response) = tungstenite::client_tls_with_config(request, stream, None, Some(connector))
+            .map_err(|e| match e {
+                HandshakeError::Failure(e) => e,
+                HandshakeError::Interrupted(_) => Error::Io(std::io::ErrorKind::WouldBlock.into()),
+            })?;
+        Ok(Self::from_socket(socket, api_key))
+    }
+
+    /// Connects like `init`, giving up if connecting or the WebSocket handshake takes longer than `timeout`.
+    ///
+    /// A timeout is reported as `ConnectionError::Connect` as well.
+    pub fn init_timeout(uri: &str, api_key: String, timeout: Duration) -> Result<Self, ConnectionError> {
+        Self::open_timeout(uri, api_key, timeout).map_err(|e| connect_error(uri, e))
+    }
+
+    fn open_timeout(uri: &str, api_key: String, timeout: Duration) -> Result<Self, Error> {
+        use std::net::ToSocketAddrs;
+        use tungstenite::client::IntoClientRequest;
+        use tungstenite::handshake::HandshakeError;
+
+        let request = uri.into_client_request()?;
+        let (host, port) = host_and_port(&request)?;
+        let mut last_error = std::io::Error::from(std::io::ErrorKind::AddrNotAvailable);
+        let mut connected = None;
+        for addr in (host.as_str(), port).to_socket_addrs()? {
+            match TCPStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => {
+                    connected = Some(stream);
+                    break;
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        let stream = connected.ok_or(last_error)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        #[cfg(feature = "tls")]
+        let handshake = tungstenite::client_tls_with_config(request, stream, None, None);
+        #[cfg(not(feature = "tls"))]
+        let handshake = match request.uri().scheme_str() {
+            Some("wss") => return Err(Error::Url(tungstenite::error::UrlError::TlsFeatureNotEnabled)),
+            _ => tungstenite::client(request, MaybeTlsStream::Plain(stream)),
+        };
+        let (socket, _response) = handshake.map_err(|e| match e {
+            HandshakeError::Failure(e) => e,
+            HandshakeError::Interrupted(_) => Error::Io(std::io::ErrorKind::TimedOut.into()),
+        })?;
+        let mut conn = Self::from_socket(socket, api_key);
+        conn.set_read_timeout(None);
+        if let MaybeTlsStream::Plain(stream) = conn.socket.get_mut() {
+            stream.set_write_timeout(None)?;
+        }
+        Ok(conn)
+    }
+}
+
+impl<S: Transport> Connection<S> {
+    /// Runs a connection on a WebSocket that is connected already, e.g. over an in-memory pipe in tests.
+    ///
+    /// The handshake has to be completed and the socket has to have the client role.
+    pub fn from_socket(socket: WebSocket<S>, api_key: String) -> Self {
+        let conn = Self { 
+            socket, 
             api_acces_key: api_key, 
             interpreter_id: Uuid::new_v4().to_string(),
             replies: HashMap::new(), 
-            encoder: CdcEncoder::new() 
-        })
+            reply_order: VecDeque::new(),
+            max_buffered_replies: DEFAULT_MAX_BUFFERED_REPLIES,
+            encoder: CdcEncoder::new(),
+            log_config: LogConfig::new(),
+            item_scratch: Vec::new(),
+            abandoned: HashSet::new(),
+            pending: HashSet::new(),
+            default_stage: crate::Stage::Current,
+            keep_last_reply: false,
+            last_reply: None,
+            line: None,
+            default_kwargs: HashMap::new(),
+            idempotent: HashMap::new(),
+            translations: TranslationCache::default(),
+            request_ids: Box::new(Uuid::new_v4),
+            negotiate_capabilities: false,
+            server_capabilities: Capabilities::NONE,
+        };
+        if let Err(e) = conn.set_socket_config(&SocketConfig::default()) {
+            log::warn!("Failed to configure the socket: {}", e);
+        }
+        conn
+    }
+
+    /// Applies TCP settings to the socket of the connection.
+    ///
+    /// New connections use `SocketConfig::default()`, i.e. `TCP_NODELAY` is enabled. The kernel
+    /// buffer sizes can only be set on Unix; elsewhere setting them fails with `ErrorKind::Unsupported`.
+    pub fn set_socket_config(&self, config: &SocketConfig) -> std::io::Result<()> {
+        let Some(stream) = self.tcp_stream() else { return Ok(()) };
+        stream.set_nodelay(config.nodelay)?;
+        #[cfg(unix)]
+        {
+            if let Some(size) = config.recv_buffer_size {
+                set_socket_buffer_size(stream, libc::SO_RCVBUF, size)?;
+            }
+            if let Some(size) = config.send_buffer_size {
+                set_socket_buffer_size(stream, libc::SO_SNDBUF, size)?;
+            }
+        }
+        #[cfg(not(unix))]
+        if config.recv_buffer_size.is_some() || config.send_buffer_size.is_some() {
+            return Err(std::io::ErrorKind::Unsupported.into());
+        }
+        Ok(())
+    }
+    /// Returns the TCP stream underneath the WebSocket
+    fn tcp_stream(&self) -> Option<&TCPStream> {
+        self.socket.get_ref().tcp_stream()
+    }
+
+    pub fn set_log_config(&mut self, config: LogConfig) {
+        self.log_config = config;
+    }
+    pub fn log_config(&self) -> &LogConfig {
+        &self.log_config
+    }
+
+    /// Retains the raw frame of the most recent reply for debugging, see `last_reply_bytes`.
+    ///
+    /// Disabled by default; disabling it drops the retained frame.
+    pub fn set_keep_last_reply(&mut self, keep: bool) {
+        self.keep_last_reply = keep;
+        if !keep {
+            self.last_reply = None;
+        }
+    }
+    /// Returns the raw frame of the most recent reply or error message, if retaining it is enabled
+    pub fn last_reply_bytes(&self) -> Option<&[u8]> {
+        self.last_reply.as_deref()
+    }
+    /// Advertises the client capabilities in the `REGISTER` request, see `Capabilities`.
+    ///
+    /// Disabled by default, so the request only carries the parameters every server knows.
+    /// Has to be set before `register`.
+    pub fn set_negotiate_capabilities(&mut self, negotiate: bool) {
+        self.negotiate_capabilities = negotiate;
+    }
+    /// Replaces the generator of request ids, e.g. with a sequential one so tests can assert the exact request bytes.
+    ///
+    /// The generated ids must be unique among the pending requests. Defaults to `Uuid::new_v4`.
+    pub fn set_request_id_generator(&mut self, generator: impl FnMut() -> Uuid + Send + 'static) {
+        self.request_ids = Box::new(generator);
+    }
+    /// Sets the stage used for items whose stage is `CURRENT_STAGE`
+    pub fn set_default_stage(&mut self, stage: crate::Stage) {
+        self.default_stage = stage;
+    }
+    pub fn default_stage(&self) -> crate::Stage {
+        self.default_stage
+    }
+    /// Sets keyword arguments added to every command executed through this connection.
+    ///
+    /// Keyword arguments passed to `execute_command` take precedence: a default is only added if
+    /// the call doesn't pass the same key itself.
+    pub fn set_default_kwargs(&mut self, kwargs: enc::CdcDict) {
+        self.default_kwargs = kwargs;
+    }
+    pub fn default_kwargs(&self) -> &enc::CdcDict {
+        &self.default_kwargs
+    }
+    /// Adds the default keyword arguments missing from `kwargs`
+    pub(crate) fn merge_default_kwargs(&self, mut kwargs: enc::CdcDict) -> enc::CdcDict {
+        for (key, value) in &self.default_kwargs {
+            kwargs.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+        kwargs
+    }
+    /// Keeps up to `capacity` translations of `crate::tr` on this connection, dropping the least
+    /// recently used ones first.
+    ///
+    /// Disabled by default (capacity 0), since the translations change if the application language
+    /// does. Lowering the capacity evicts entries right away.
+    pub fn set_translation_cache_capacity(&mut self, capacity: usize) {
+        self.translations.capacity = capacity;
+        let excess = self.translations.entries.len().saturating_sub(capacity);
+        self.translations.evict(excess);
+    }
+    /// Forgets all cached translations, e.g. after the application language changed
+    pub fn clear_translation_cache(&mut self) {
+        self.translations.clear();
+    }
+    /// Returns the cached translation of `text` with translation id `id`
+    pub(crate) fn cached_translation(&mut self, text: &str, id: Option<&str>) -> Option<String> {
+        self.translations.get(text, id)
+    }
+    pub(crate) fn cache_translation(&mut self, text: &str, id: Option<&str>, translation: &str) {
+        self.translations.insert(text, id, translation);
+    }
+    /// Runs an API snippet on the server and returns its result.
+    ///
+    /// # Arguments
+    /// * `code` - The source of the snippet
+    /// * `params` - Values made available to the snippet
+    pub fn run_api(&mut self, code: &str, params: enc::CdcDict) -> Result<enc::CdcValue, ConnectionError> {
+        self.request(Request::RUNAPI, crate::params::RunApiParams { code, params })
+    }
+    /// Reads a setting of the GOM application.
+    ///
+    /// # Arguments
+    /// * `key` - The name of the setting
+    pub fn get_configuration(&mut self, key: &str) -> Result<enc::CdcValue, ConnectionError> {
+        self.request(Request::CONFIGURATION, crate::params::ConfigurationParams { key, write: false, value: None })
+    }
+    /// Changes a setting of the GOM application, e.g. before running commands that depend on it.
+    ///
+    /// # Arguments
+    /// * `key` - The name of the setting
+    /// * `value` - The new value of the setting
+    pub fn set_configuration(&mut self, key: &str, value: enc::CdcValue) -> Result<(), ConnectionError> {
+        self.request(Request::CONFIGURATION, crate::params::ConfigurationParams { key, write: true, value: Some(value) }).map(|_| ())
+    }
+    /// Sends one of the test requests and returns the reply of the server.
+    ///
+    /// The reply depends on the server build; the test requests exist to check that a server
+    /// understands this client, see the `conformance` module.
+    pub fn run_protocol_test(&mut self, which: TestCase) -> Result<enc::CdcValue, ConnectionError> {
+        self.request(which.request(), ())
+    }
+    /// Reports the file and line the host is currently executing to the server.
+    ///
+    /// The server uses it as context for its own error reports; errors returned by later requests
+    /// carry it as well, see `ServerError::line` and `CdcError::line`.
+    pub fn set_line(&mut self, file: &str, line: i64) -> Result<(), ConnectionError> {
+        self.line = Some((file.to_string(), line));
+        self.request(Request::LINE, crate::params::LineParams { file, line }).map(|_| ())
+    }
+    /// Returns the file and line last reported with `set_line`
+    pub fn line(&self) -> Option<(&str, i64)> {
+        self.line.as_ref().map(|(file, line)| (file.as_str(), *line))
+    }
+    /// Substitutes the default stage if `stage` is `Stage::Current`
+    pub(crate) fn resolve_stage(&self, stage: crate::Stage) -> crate::Stage {
+        match stage {
+            crate::Stage::Current => self.default_stage,
+            stage => stage,
+        }
+    }
+    /// Returns the stage of `item` on the wire, substituting the default stage for `CURRENT_STAGE`
+    pub(crate) fn item_stage(&self, item: &crate::Item) -> i64 {
+        match item.stage_enum() {
+            Some(stage) => self.resolve_stage(stage).into(),
+            // Raw stages without a `Stage` are sent unchanged
+            None => enc::cat_to_wire(item.stage),
+        }
+    }
+
+    /// Returns the counters of the encoder used for outgoing messages
+    pub fn encoder_stats(&self) -> enc::EncoderStats {
+        self.encoder.stats()
+    }
+    /// Returns the callables sent to the server so far, which it may `CALL` back
+    pub fn registered_callables(&self) -> Vec<enc::CallableHandle> {
+        self.encoder.registered_callables()
+    }
+    /// Returns the number of callables sent to the server so far
+    pub fn callable_count(&self) -> usize {
+        self.encoder.callable_count()
+    }
+    /// Forgets all callables sent to the server, e.g. between sessions
+    pub fn clear_callables(&mut self) {
+        self.encoder.clear_callables();
     }
 
     pub fn register(&mut self, interpreter_id: &str, filename: &str) -> Result<enc::CdcValue, ConnectionError> {
+        self.register_cancellable(interpreter_id, filename, None)
+    }
+    /// Registers like `register`, failing with `ConnectionError::Timeout` if the server doesn't answer in time
+    pub fn register_timeout(&mut self, interpreter_id: &str, filename: &str, timeout: Duration) -> Result<enc::CdcValue, ConnectionError> {
+        self.register_cancellable(interpreter_id, filename, Some(timeout))
+    }
+    fn register_cancellable(&mut self, interpreter_id: &str, filename: &str, timeout: Option<Duration>) -> Result<enc::CdcValue, ConnectionError> {
         // Store the interpreter_id for future use in all messages
         self.interpreter_id = interpreter_id.to_string();
         
-        let mut params = std::collections::HashMap::new();
-        params.insert("id".to_string(), enc::CdcValue::STRING(interpreter_id.to_string()));
-        params.insert("file".to_string(), enc::CdcValue::STRING(filename.to_string()));
-        self.request(Request::REGISTER, params)
+        let params = crate::params::RegisterParams {
+            id: interpreter_id,
+            file: filename,
+            capabilities: self.negotiate_capabilities.then(|| Capabilities::client().bits() as i64),
+        };
+        let reply = self.request_cancellable(Request::REGISTER, params, timeout, None)?;
+        // Servers that don't negotiate don't answer with their capabilities
+        self.server_capabilities = match enc::CdcDict::try_from(reply.clone()) {
+            Ok(map) => match map.get(crate::params::keys::CAPABILITIES) {
+                Some(enc::CdcValue::INTEGER(bits)) => Capabilities::from_bits(*bits as u64),
+                _ => Capabilities::NONE,
+            },
+            Err(_) => Capabilities::NONE,
+        };
+        Ok(reply)
+    }
+    /// Releases the interpreter on the server and closes the WebSocket.
+    ///
+    /// The socket is closed even if the server doesn't answer the `RELEASE` request in time,
+    /// in which case its error is returned.
+    pub fn close(mut self) -> Result<(), ConnectionError> {
+        let id = self.interpreter_id.clone();
+        let released = self.request_cancellable(Request::RELEASE, crate::params::ReleaseParams { id: &id }, Some(CLOSE_TIMEOUT), None);
+        self.set_read_timeout(Some(CLOSE_TIMEOUT));
+        if self.socket.close(None).is_ok() {
+            // Wait for the close frame of the server, discarding everything before it
+            while self.socket.read().is_ok() {}
+        }
+        released.map(|_| ())
+    }
+    /// Returns the id the interpreter is registered with, e.g. to find it in the interpreter list.
+    ///
+    /// Until `register` is called this is a random id that the server doesn't know.
+    pub fn interpreter_id(&self) -> &str {
+        &self.interpreter_id
+    }
+    /// Returns the capabilities the server announced at registration
+    pub fn server_capabilities(&self) -> Capabilities {
+        self.server_capabilities
+    }
+    /// Returns the capabilities both this client and the server support
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::client() & self.server_capabilities
+    }
+    /// Encodes and sends a message, failing instead of panicking if the value can't be encoded
+    fn send(&mut self, value: &enc::CdcValue) -> Result<(), ConnectionError> {
+        let bytes = Bytes::from(self.encoder.try_encode(value)?);
+        self.socket.send(Message::Binary(bytes)).map_err(|e| {
+            log::error!("Failed to send a message to the server: {}", e);
+            ConnectionError::SendFailed
+        })
+    }
+    /// Returns the map describing `item`, reusing an item map of a previous request
+    pub(crate) fn item_map(&mut self, item: &crate::Item) -> enc::CdcDict {
+        let mut map = self.item_scratch.pop().unwrap_or_default();
+        item.write_to_map(&mut map);
+        if item.stage_enum() == Some(crate::Stage::Current) {
+            if let Some(stage) = map.get_mut("stage") {
+                *stage = enc::CdcValue::INTEGER(self.default_stage.into());
+            }
+        }
+        map
+    }
+    /// Sets the read timeout of the underlying TCP stream
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        let result = match self.tcp_stream() {
+            Some(stream) => stream.set_read_timeout(timeout),
+            None => Ok(()),
+        };
+        if let Err(e) = result {
+            log::warn!("Failed to set the socket read timeout: {}", e);
+        }
+    }
+    /// Returns true if the message is the reply to an abandoned request, which is then forgotten
+    fn take_abandoned(&mut self, envelope: &Envelope) -> bool {
+        match &envelope.id {
+            Some(id) => Uuid::parse_str(id).is_ok_and(|id| self.abandoned.remove(&id)),
+            None => false,
+        }
+    }
+    pub fn request(&mut self, command: Request, params: impl ToParams) -> Result<enc::CdcValue, ConnectionError> {
+        self.request_cancellable(command, params, None, None)
+    }
+    /// Sends a request on `item` like `request`, reporting `ConnectionError::StaleItem` if the
+    /// server no longer knows the item.
+    ///
+    /// An attribute or index error is ambiguous, so the item itself is probed with a `REPR`
+    /// request before the error is classified. The probe is only sent after a failure.
+    pub(crate) fn item_request(&mut self, item: &crate::Item, command: Request, params: impl ToParams) -> Result<enc::CdcValue, ConnectionError> {
+        match self.request(command, params) {
+            Err(err @ (ConnectionError::Attribute(_) | ConnectionError::Index(_))) => match self.item_exists(item) {
+                Ok(false) => Err(ConnectionError::StaleItem),
+                _ => Err(err),
+            },
+            result => result,
+        }
     }
-    fn send(&mut self, value: enc::CdcValue) -> Result<(), Error> {
-        let bytes = Bytes::from(self.encoder.encode(value));
-        self.socket.send(Message::Binary(bytes))
+    /// Checks with a `REPR` request whether the server still knows `item`
+    pub(crate) fn item_exists(&mut self, item: &crate::Item) -> Result<bool, ConnectionError> {
+        let params = crate::params::ItemParams { item: self.item_map(item) };
+        match self.request(Request::REPR, params) {
+            Ok(_) => Ok(true),
+            Err(ConnectionError::Attribute(_) | ConnectionError::Index(_)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+    /// Sends a request and waits for its reply, giving up after `timeout` or once `cancel` is set.
+    ///
+    /// The cancel flag is checked between frames, so a frame is never left half-read. The reply of
+    /// an abandoned request is discarded when it arrives later.
+    pub fn request_cancellable(&mut self, command: Request, params: impl ToParams, timeout: Option<Duration>, cancel: Option<&AtomicBool>) -> Result<enc::CdcValue, ConnectionError> {
+        let request_id = self.send_request(command, params.into_params())?;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let reply = self.wait_for_reply(request_id, deadline, cancel)?;
+        self.finish_request(command, request_id, reply)
+    }
+    /// Sends a request like `request_cancellable`, at most once per idempotency `key`.
+    ///
+    /// If an earlier request with the same key timed out or was cancelled, it is not sent again:
+    /// the call resumes waiting for the reply to the original request, which is kept if it arrived
+    /// in the meantime. A command is therefore executed at most once per key and connection, no
+    /// matter how often the caller retries. The server has no deduplication of its own, so the
+    /// guarantee does not extend to a new connection.
+    ///
+    /// The key is released once a reply arrived; using it again afterwards sends a new request.
+    /// Keys of requests that are never retried keep their reply until the connection is dropped.
+    pub fn request_idempotent(&mut self, key: &str, command: Request, params: impl ToParams, timeout: Option<Duration>, cancel: Option<&AtomicBool>) -> Result<enc::CdcValue, ConnectionError> {
+        let request_id = match self.idempotent.get(key) {
+            Some(request_id) => *request_id,
+            None => {
+                let request_id = self.send_request(command, params.into_params())?;
+                self.idempotent.insert(key.to_string(), request_id);
+                request_id
+            }
+        };
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let reply = match self.wait_for_reply(request_id, deadline, cancel) {
+            Ok(reply) => reply,
+            // The request is still in flight, so a retry can resume waiting for it
+            Err(err @ (ConnectionError::Timeout | ConnectionError::Cancelled)) => return Err(err),
+            Err(err) => {
+                self.idempotent.remove(key);
+                self.abandon(request_id);
+                return Err(err);
+            }
+        };
+        self.idempotent.remove(key);
+        self.finish_request(command, request_id, reply)
+    }
+    /// Sends a request like `request`, also returning the id it was sent with.
+    ///
+    /// The id appears in the server log and in this crate's log output, so it can be used to
+    /// correlate both sides of a request.
+    pub fn request_with_id(&mut self, command: Request, params: impl ToParams) -> (Uuid, Result<enc::CdcValue, ConnectionError>) {
+        let request_id = (self.request_ids)();
+        let result = self
+            .send_request_as(request_id, command, params.into_params())
+            .and_then(|()| self.wait_for_reply(request_id, None, None))
+            .and_then(|reply| self.finish_request(command, request_id, reply));
+        (request_id, result)
+    }
+    /// Sends several requests before waiting for any reply, so their round trips overlap.
+    ///
+    /// The results are returned in the order of `requests`.
+    pub fn request_batch(&mut self, requests: Vec<(Request, enc::CdcDict)>) -> Vec<Result<enc::CdcValue, ConnectionError>> {
+        let sent: Vec<(Request, Result<Uuid, ConnectionError>)> = requests
+            .into_iter()
+            .map(|(command, params)| (command, self.send_request(command, params)))
+            .collect();
+        sent.into_iter()
+            .map(|(command, request_id)| {
+                let request_id = request_id?;
+                let reply = self.wait_for_reply(request_id, None, None)?;
+                self.finish_request(command, request_id, reply)
+            })
+            .collect()
+    }
+    /// Encodes and sends a request, returning its id
+    fn send_request(&mut self, command: Request, params: enc::CdcDict) -> Result<Uuid, ConnectionError> {
+        let request_id = (self.request_ids)();
+        self.send_request_as(request_id, command, params)?;
+        Ok(request_id)
+    }
+    /// Encodes and sends a request with the given id
+    fn send_request_as(&mut self, request_id: Uuid, command: Request, params: enc::CdcDict) -> Result<(), ConnectionError> {
+        let log_level = self.log_config.level_for(command);
+        if let Some(level) = log_level.to_level() {
+            log::log!(level, "Sending {:?} request {}", command, request_id);
+        }
+        let params = enc::CdcValue::MAP(params);
+        if log::log_enabled!(log::Level::Warn) {
+            let duplicates = params.duplicate_item_count();
+            if duplicates >= DUPLICATE_ITEM_WARNING {
+                log::warn!("{:?} request {} repeats items {} times, each repetition is sent in full", command, request_id, duplicates);
+            }
+        }
+        if log_level != log::LevelFilter::Off && log::log_enabled!(log::Level::Trace) {
+            log::trace!("Request {} params: {}", request_id, self.log_config.format_payload(&params));
+        }
+        let message = self.request_message(request_id, command, params);
+        self.send(&message)?;
+        self.pending.insert(request_id);
+        // Keep the item maps of the request around so the next item requests can reuse them.
+        // Maps nested deeper, like those of a command selection, are dropped.
+        if let enc::CdcValue::MAP(mut message) = message {
+            if let Some(enc::CdcValue::MAP(mut params)) = message.remove(connection::attribute::PARAMS) {
+                for key in [crate::params::keys::ITEM, crate::params::keys::OTHER, crate::params::keys::OBJECT] {
+                    if let Some(enc::CdcValue::MAP(item)) = params.remove(key) {
+                        if self.item_scratch.len() < ITEM_SCRATCH_MAPS {
+                            self.item_scratch.push(item);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
     }
-    pub fn request(&mut self, command: Request, params: std::collections::HashMap<String, enc::CdcValue>) -> Result<enc::CdcValue, ConnectionError> {
-        let request_id = Uuid::new_v4();
+    /// Builds the message of a request
+    fn request_message(&self, request_id: Uuid, command: Request, params: enc::CdcValue) -> enc::CdcValue {
         let mut map: std::collections::HashMap<String, enc::CdcValue> = std::collections::HashMap::new();
         map.insert(connection::attribute::TYPE.into(), enc::CdcValue::STRING(connection::attribute::types::REQUEST.into()));
         map.insert(connection::attribute::APIKEY.into(), enc::CdcValue::STRING(self.api_acces_key.clone()));
         map.insert(connection::attribute::ID.into(), enc::CdcValue::STRING(request_id.to_string()));
         map.insert(connection::attribute::VALUE.into(), enc::CdcValue::INTEGER(command as i64));
-        map.insert(connection::attribute::PARAMS.into(), enc::CdcValue::MAP(params));
+        map.insert(connection::attribute::PARAMS.into(), params);
         map.insert(connection::attribute::INTERPRETER.into(), enc::CdcValue::STRING(self.interpreter_id.clone()));
-        let _ = self.send(enc::CdcValue::MAP(map)).expect("Could not send the request!");
-
+        enc::CdcValue::MAP(map)
+    }
+    /// Encodes a request into the frame `request` would send, without sending it.
+    ///
+    /// Returns the id of the request along with the bytes. The request is not pending, so a reply
+    /// to it is attributed to no request. Callables in `params` are registered like for a sent request.
+    pub fn encode_request(&mut self, command: Request, params: enc::CdcDict) -> (Uuid, Vec<u8>) {
+        let request_id = (self.request_ids)();
+        let message = self.request_message(request_id, command, enc::CdcValue::MAP(params));
+        (request_id, self.encoder.encode_ref(&message))
+    }
+    /// Reads messages until the reply to `request_id` arrived, the deadline passed or `cancel` is set
+    fn wait_for_reply(&mut self, request_id: Uuid, deadline: Option<Instant>, cancel: Option<&AtomicBool>) -> Result<connection::reply::Reply, ConnectionError> {
         while !(self.replies.contains_key(&request_id)){
-            let msg = self.socket.read().expect("Couldn't read from the socket!");
-            let msg =self.encoder.decode_value(&mut msg.into_data().as_ref()).expect("Couldn't decode the a reply from the server"); 
-            let mut msg_dict = msg.expect_map();
-            let msg_type = msg_dict.remove(connection::attribute::TYPE).expect("Type missing from msg dict");
-            let msg_type = msg_type.expect_string();
-            match &msg_type[..] {
-                connection::attribute::types::ERROR => {
-                    let reply = connection::reply::Error{
-                        error_type: msg_dict.remove(connection::attribute::TYPE).expect("Missing type key in error").expect_string(),
-                        description: msg_dict.remove(connection::attribute::DESCRIPTION).expect("Missing description key in error").expect_string().clone(),
-                        code: msg_dict.remove(connection::attribute::CODE).expect("Missing code key in error").expect_int() as i64,
-                        log: msg_dict.remove(connection::attribute::LOG).expect("Missing log key in error").expect_string().clone(),
-                        value: Bytes::from(msg_dict.remove(connection::attribute::VALUE).expect("Missing value key in error").expect_blob()),
-                    };
-                    self.replies.insert(request_id, connection::reply::Reply::ERROR(reply));
-                },
-                connection::attribute::types::REPLY => {
-                    let reply_value = msg_dict.get(connection::attribute::VALUE).expect("Missing value key in reply").clone();
-                    self.replies.insert(request_id, connection::reply::Reply::REPLY(reply_value));
-                },
-                connection::attribute::types::WAIT => {
-                    // Ignore wait messages
-                },
-                connection::attribute::types::CALL => {
-                    let func = msg_dict.get(connection::attribute::VALUE).expect("Missing value key in call").clone().expect_callable();
-                    let args = msg_dict.get(connection::attribute::ARGS).expect("Missing args key in call").clone().expect_list();
-                    let kwargs = msg_dict.get(connection::attribute::KWARGS).expect("Missing kwargs key in call").clone().expect_map();
-                    let result = func(args, kwargs);
-                    let r = self.send(result);
-                    if r.is_err(){
-                        panic!("Failed to send call result back to server!");
+            if cancel.is_some_and(|cancel| cancel.load(Ordering::SeqCst)) {
+                self.abandon(request_id);
+                return Err(ConnectionError::Cancelled);
+            }
+            let mut wait = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        self.abandon(request_id);
+                        return Err(ConnectionError::Timeout);
                     }
-                },
-                _ => {
-                    panic!("Unknown message type received: {}", msg_type);
+                    Some(deadline - now)
+                }
+                None => None,
+            };
+            if cancel.is_some() {
+                wait = Some(wait.map_or(CANCEL_POLL_INTERVAL, |wait| wait.min(CANCEL_POLL_INTERVAL)));
+            }
+            if deadline.is_some() || cancel.is_some() {
+                self.set_read_timeout(wait);
+            }
+            let msg = match self.read_message() {
+                Ok(Some(msg)) => msg,
+                // The frame is buffered by the socket, so reading can resume after a timeout
+                Ok(None) => continue,
+                Err(err) => {
+                    self.abandon(request_id);
+                    return Err(err);
+                }
+            };
+            if let Err(err) = self.handle_message(msg, request_id) {
+                self.abandon(request_id);
+                return Err(err);
+            }
+            if !self.pending.contains(&request_id) && !self.replies.contains_key(&request_id) {
+                self.set_read_timeout(None);
+                return Err(ConnectionError::ReplyDropped);
+            }
+        }
+        if deadline.is_some() || cancel.is_some() {
+            self.set_read_timeout(None);
+        }
+        self.pending.remove(&request_id);
+        Ok(self.replies.remove(&request_id).expect("Ended receiving loop before the message was received!"))
+    }
+    /// Reads and processes up to `max` messages the server already sent, without sending a request.
+    ///
+    /// Returns the number of handled messages. Unlike `request`, this doesn't block when no message
+    /// is waiting, so hosts can service `CALL`s from the server while they are idle. Replies to
+    /// requests that are still pending are buffered for them.
+    pub fn pump(&mut self, max: usize) -> Result<usize, ConnectionError> {
+        self.set_nonblocking(true);
+        let mut handled = 0;
+        let result = loop {
+            if handled >= max {
+                break Ok(handled);
+            }
+            let msg = match self.read_message() {
+                Ok(Some(msg)) => msg,
+                Ok(None) => break Ok(handled),
+                Err(err) => break Err(err),
+            };
+            if let Err(err) = self.handle_message(msg, Uuid::nil()) {
+                break Err(err);
+            }
+            handled += 1;
+        };
+        self.set_nonblocking(false);
+        if self.replies.remove(&Uuid::nil()).is_some() {
+            log::warn!("Discarding a reply without a known request id");
+        }
+        result
+    }
+    /// Reads the next data message from the socket.
+    ///
+    /// Returns `None` if no message arrived before the read timeout, or if the frame was a ping or
+    /// pong, which the socket answers itself. A close frame or a failed read ends the connection.
+    fn read_message(&mut self) -> Result<Option<Message>, ConnectionError> {
+        match self.socket.read() {
+            Ok(msg @ (Message::Binary(_) | Message::Text(_))) => Ok(Some(msg)),
+            Ok(Message::Close(frame)) => Err(ConnectionError::ConnectionClosed(frame.map_or_else(|| "closed by the server".to_string(), |frame| frame.to_string()))),
+            Ok(_) => Ok(None),
+            Err(Error::Io(e)) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => Ok(None),
+            Err(e) => Err(ConnectionError::ConnectionClosed(e.to_string())),
+        }
+    }
+    fn set_nonblocking(&mut self, nonblocking: bool) {
+        let result = match self.tcp_stream() {
+            Some(stream) => stream.set_nonblocking(nonblocking),
+            None => Ok(()),
+        };
+        if let Err(e) = result {
+            log::warn!("Failed to set the socket to non-blocking: {}", e);
+        }
+    }
+    /// Buffers a reply until its request collects it, evicting the oldest reply if the buffer is full
+    fn store_reply(&mut self, request_id: Uuid, reply: connection::reply::Reply) {
+        self.replies.insert(request_id, reply);
+        self.reply_order.push_back(request_id);
+        while self.replies.len() > self.max_buffered_replies {
+            let Some(oldest) = self.reply_order.pop_front() else { break };
+            if self.replies.remove(&oldest).is_some() {
+                log::warn!("Dropping the reply to request {}, more than {} replies are buffered", oldest, self.max_buffered_replies);
+                self.pending.remove(&oldest);
+                self.idempotent.retain(|_, id| *id != oldest);
+            }
+        }
+        if self.reply_order.len() > 2 * self.replies.len() {
+            let replies = &self.replies;
+            self.reply_order.retain(|id| replies.contains_key(id));
+        }
+    }
+    /// Limits the number of replies that arrived but were not collected by their request yet.
+    ///
+    /// Replies only pile up through replies to idempotent requests that are never retried, or
+    /// a desync with the server. Beyond the limit the oldest reply is dropped with a warning and
+    /// its request fails with `ConnectionError::ReplyDropped`.
+    pub fn set_max_buffered_replies(&mut self, max: usize) {
+        self.max_buffered_replies = max;
+    }
+    /// Returns the number of replies that arrived but were not collected by their request yet
+    pub fn buffered_reply_count(&self) -> usize {
+        self.replies.len()
+    }
+    /// Forgets a request whose reply is no longer awaited.
+    ///
+    /// Idempotent requests stay pending, so their reply is kept for a retry.
+    fn abandon(&mut self, request_id: Uuid) {
+        self.set_read_timeout(None);
+        if self.idempotent.values().any(|id| *id == request_id) {
+            return;
+        }
+        self.pending.remove(&request_id);
+        self.abandoned.insert(request_id);
+    }
+    /// Returns the pending request a message replies to, falling back to `default_id`
+    /// for messages that carry no known request id
+    fn reply_target(&self, envelope: &Envelope, default_id: Uuid) -> Uuid {
+        match &envelope.id {
+            Some(id) => Uuid::parse_str(id).ok().filter(|id| self.pending.contains(id)).unwrap_or(default_id),
+            None => default_id,
+        }
+    }
+    /// Processes one message from the server while waiting for the reply to `default_id`
+    fn handle_message(&mut self, msg: Message, default_id: Uuid) -> Result<(), ConnectionError> {
+        let data = msg.into_data();
+        let msg = match self.encoder.decode_complete(&data) {
+            Err(enc::DecodeError::TrailingBytes(len)) => {
+                // Keep the value; the extra bytes point to a framing bug on one of the sides
+                log::warn!("Message from the server has {} bytes after its value", len);
+                self.encoder.decode_value(&mut data.as_ref())
+            }
+            result => result,
+        }?;
+        let envelope = Envelope::from_value(msg)?;
+        if self.keep_last_reply && matches!(envelope.kind, EnvelopeKind::Reply(_) | EnvelopeKind::Error(_)) {
+            self.last_reply = Some(data);
+        }
+        if matches!(envelope.kind, EnvelopeKind::Reply(_) | EnvelopeKind::Error(_)) && self.take_abandoned(&envelope) {
+            return Ok(());
+        }
+        let request_id = self.reply_target(&envelope, default_id);
+        match envelope.kind {
+            EnvelopeKind::Error(error) => {
+                let reply = connection::reply::Error{
+                    error_type: error.error_type,
+                    description: error.description,
+                    code: error.code,
+                    log: error.log,
+                    value: Bytes::from(error.value),
+                };
+                self.store_reply(request_id, connection::reply::Reply::ERROR(reply));
+            },
+            // The result of a RUNAPI request is delivered like a reply
+            EnvelopeKind::Reply(value) => {
+                self.store_reply(request_id, connection::reply::Reply::REPLY(value));
+            },
+            EnvelopeKind::Wait => {
+                // Ignore wait messages
+            },
+            EnvelopeKind::Call { function, args, kwargs } => {
+                let result = function(args, kwargs);
+                if let Err(err) = self.send(&result) {
+                    log::error!("Failed to send call result back to server: {}", err);
+                    return Err(err);
+                }
+            },
+            EnvelopeKind::Other(_) => {
+                // Newer servers may send message types this client doesn't know yet
+                log::warn!("Ignoring message of unknown type {:?}", envelope.msg_type);
+            }
+        }
+        Ok(())
+    }
+    /// Logs the reply of a request and converts it into the request result
+    fn finish_request(&mut self, command: Request, request_id: Uuid, reply: connection::reply::Reply) -> Result<enc::CdcValue, ConnectionError> {
+        if let Some(level) = self.log_config.level_for(command).to_level() {
+            match &reply {
+                connection::reply::Reply::ERROR(err) => log::log!(level, "{:?} request {} failed: {}", command, request_id, err.description),
+                connection::reply::Reply::REPLY(value) => {
+                    log::log!(level, "{:?} request {} succeeded", command, request_id);
+                    if log::log_enabled!(log::Level::Trace) {
+                        log::trace!("Reply {} value: {}", request_id, self.log_config.format_payload(value));
+                    }
+                }
+            }
+        }
+        reply.into_result().map_err(|err| self.with_line(err))
+    }
+    /// Adds the line last reported with `set_line` to an error that has none
+    fn with_line(&self, err: ConnectionError) -> ConnectionError {
+        let Some((file, line)) = &self.line else { return err };
+        match err {
+            ConnectionError::CdcError(mut err) if err.line <= 0 => {
+                err.line = *line;
+                ConnectionError::CdcError(err)
+            }
+            ConnectionError::Attribute(err) => ConnectionError::Attribute(err.at(file, *line)),
+            ConnectionError::Import(err) => ConnectionError::Import(err.at(file, *line)),
+            ConnectionError::Index(err) => ConnectionError::Index(err.at(file, *line)),
+            ConnectionError::Break(err) => ConnectionError::Break(err.at(file, *line)),
+            ConnectionError::Server(err) => ConnectionError::Server(err.at(file, *line)),
+            err => err,
+        }
+    }
+}
+/// A request queued for the reader thread of an `AsyncConnection`
+#[cfg(feature = "async")]
+struct QueuedRequest {
+    priority: RequestPriority,
+    command: Request,
+    params: enc::CdcDict,
+    reply: oneshot::Sender<Result<enc::CdcValue, ConnectionError>>,
+}
+
+/// How urgently a request of an `AsyncConnection` is sent, see `AsyncConnection::request_async`
+#[cfg(feature = "async")]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RequestPriority {
+    /// Bulk work like exports, sent when nothing more urgent is queued
+    Low,
+    #[default]
+    Normal,
+    /// Requests a user waits for, e.g. to refresh a view
+    High,
+}
+
+/// A queued request in the send order of the reader thread
+#[cfg(feature = "async")]
+struct Scheduled {
+    priority: RequestPriority,
+    /// The queue position, which keeps requests of the same priority in order
+    sequence: u64,
+    request: QueuedRequest,
+}
+#[cfg(feature = "async")]
+impl Scheduled {
+    fn key(&self) -> (RequestPriority, std::cmp::Reverse<u64>) {
+        (self.priority, std::cmp::Reverse(self.sequence))
+    }
+}
+#[cfg(feature = "async")]
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+#[cfg(feature = "async")]
+impl Eq for Scheduled {}
+#[cfg(feature = "async")]
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+#[cfg(feature = "async")]
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// The requests that are queued but not sent yet, highest priority first and in queue order otherwise
+#[cfg(feature = "async")]
+#[derive(Default)]
+struct Schedule {
+    queued: std::collections::BinaryHeap<Scheduled>,
+    next_sequence: u64,
+}
+#[cfg(feature = "async")]
+impl Schedule {
+    fn push(&mut self, priority: RequestPriority, request: QueuedRequest) {
+        self.queued.push(Scheduled { priority, sequence: self.next_sequence, request });
+        self.next_sequence += 1;
+    }
+    fn pop(&mut self) -> Option<QueuedRequest> {
+        self.queued.pop().map(|scheduled| scheduled.request)
+    }
+    fn is_empty(&self) -> bool {
+        self.queued.is_empty()
+    }
+}
+
+/// How long the reader thread of an `AsyncConnection` waits for a frame before sending queued
+/// requests, while other requests wait for their reply.
+///
+/// The socket can't be read and written from different threads, so newly queued requests are
+/// picked up between reads. Without requests in flight the thread blocks on the queue instead.
+#[cfg(feature = "async")]
+const BACKGROUND_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// How many requests of an `AsyncConnection` may wait for their reply at once.
+///
+/// Further requests stay queued, so a request of higher priority can still overtake them.
+#[cfg(feature = "async")]
+const MAX_IN_FLIGHT: usize = 32;
+
+/// A connection whose socket is owned by a background reader thread.
+///
+/// Requests can be queued from any thread; their replies are delivered through oneshot channels
+/// that can be awaited or blocked on. Replies may arrive in any order, and WAIT and CALL messages
+/// are handled on the reader thread. The thread exits once the `AsyncConnection` is dropped and
+/// all queued requests have been answered.
+///
+/// The reader loop is separate from the request methods of `Connection`, which stay synchronous
+/// and don't need a thread. Only `AsyncConnection::request` is built on the async path: it
+/// blocks on the receiver of `request_async`.
+#[cfg(feature = "async")]
+pub struct AsyncConnection {
+    requests: std::sync::mpsc::Sender<QueuedRequest>,
+    /// Why the reader thread stopped, set before it exits on an error
+    stopped: std::sync::Arc<std::sync::OnceLock<String>>,
+}
+
+#[cfg(feature = "async")]
+impl AsyncConnection {
+    /// Moves `connection` onto a background reader thread
+    pub fn new(connection: Connection) -> Self {
+        let (requests, queue) = std::sync::mpsc::channel();
+        let stopped = std::sync::Arc::new(std::sync::OnceLock::new());
+        let reason = stopped.clone();
+        std::thread::spawn(move || connection.serve(queue, &reason));
+        Self { requests, stopped }
+    }
+
+    /// Queues a request, returning a receiver for its result.
+    ///
+    /// Queued requests are sent highest `priority` first, so e.g. a view refresh doesn't wait
+    /// behind a bulk export. Only `MAX_IN_FLIGHT` requests are sent ahead of their replies;
+    /// requests already sent are not overtaken.
+    ///
+    /// If the reader thread stops, e.g. because the socket was closed, requests it already took
+    /// fail with `ConnectionError::ConnectionClosed`, while the receivers of later requests report
+    /// a `RecvError` instead of a result.
+    pub fn request_async(&self, command: Request, params: impl ToParams, priority: RequestPriority) -> oneshot::Receiver<Result<enc::CdcValue, ConnectionError>> {
+        let (reply, receiver) = oneshot::channel();
+        // A failed send drops `reply`, which the receiver reports as a disconnect
+        let _ = self.requests.send(QueuedRequest { priority, command, params: params.into_params(), reply });
+        receiver
+    }
+
+    /// Sends a request with `RequestPriority::Normal` and blocks the calling thread until its reply arrived.
+    ///
+    /// Fails with `ConnectionError::ConnectionClosed` and the reason the reader thread stopped
+    /// if it did.
+    pub fn request(&self, command: Request, params: impl ToParams) -> Result<enc::CdcValue, ConnectionError> {
+        self.request_async(command, params, RequestPriority::Normal).recv().unwrap_or_else(|_| {
+            let reason = self.stopped.get().cloned().unwrap_or_else(|| "the background reader stopped".to_string());
+            Err(ConnectionError::ConnectionClosed(reason))
+        })
+    }
+}
+
+#[cfg(feature = "async")]
+impl Connection {
+    /// Runs the reader loop of an `AsyncConnection`.
+    ///
+    /// On an error the reason is stored in `stopped` and every request taken from the queue fails
+    /// with it, before the queue is dropped.
+    fn serve(mut self, queue: std::sync::mpsc::Receiver<QueuedRequest>, stopped: &std::sync::OnceLock<String>) {
+        use std::sync::mpsc::TryRecvError;
+
+        let mut waiting: HashMap<Uuid, (Request, oneshot::Sender<Result<enc::CdcValue, ConnectionError>>)> = HashMap::new();
+        let mut schedule = Schedule::default();
+        let mut closed = false;
+        self.set_read_timeout(Some(BACKGROUND_POLL_INTERVAL));
+        loop {
+            // With nothing in flight no reply can arrive, so wait for a request instead of polling the socket
+            if !closed && waiting.is_empty() && schedule.is_empty() {
+                match queue.recv() {
+                    Ok(queued) => schedule.push(queued.priority, queued),
+                    Err(_) => closed = true,
                 }
             }
+            while !closed {
+                match queue.try_recv() {
+                    Ok(queued) => schedule.push(queued.priority, queued),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => closed = true,
+                }
+            }
+            while waiting.len() < MAX_IN_FLIGHT {
+                let Some(queued) = schedule.pop() else { break };
+                match self.send_request(queued.command, queued.params) {
+                    Ok(request_id) => {
+                        waiting.insert(request_id, (queued.command, queued.reply));
+                    }
+                    Err(err) => {
+                        let _ = queued.reply.send(Err(err));
+                    }
+                }
+            }
+            if closed && waiting.is_empty() && schedule.is_empty() {
+                return;
+            }
+            // Messages without a known request id can only be attributed if a single request is waiting
+            let default_id = match waiting.len() {
+                1 => *waiting.keys().next().unwrap(),
+                _ => Uuid::nil(),
+            };
+            let result = match self.read_message() {
+                Ok(Some(msg)) => self.handle_message(msg, default_id),
+                Ok(None) => continue,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                log::error!("Background reader stopped: {}", err);
+                let reason = match err {
+                    ConnectionError::ConnectionClosed(reason) => reason,
+                    err => err.to_string(),
+                };
+                let _ = stopped.set(reason.clone());
+                let senders = waiting.into_values().map(|(_, sender)| sender);
+                for sender in senders.chain(std::iter::from_fn(|| schedule.pop()).map(|queued| queued.reply)) {
+                    let _ = sender.send(Err(ConnectionError::ConnectionClosed(reason.clone())));
+                }
+                return;
+            }
+            if self.replies.remove(&Uuid::nil()).is_some() {
+                log::warn!("Discarding a reply without a known request id");
+            }
+            let dropped: Vec<Uuid> = waiting.keys().filter(|id| !self.pending.contains(id)).copied().collect();
+            for request_id in dropped {
+                let (_, sender) = waiting.remove(&request_id).unwrap();
+                let _ = sender.send(Err(ConnectionError::ReplyDropped));
+            }
+            let answered: Vec<Uuid> = self.replies.keys().filter(|id| waiting.contains_key(id)).copied().collect();
+            for request_id in answered {
+                let reply = self.replies.remove(&request_id).unwrap();
+                let (command, sender) = waiting.remove(&request_id).unwrap();
+                self.pending.remove(&request_id);
+                let _ = sender.send(self.finish_request(command, request_id, reply));
+            }
+        }
+    }
+}
+
+/// Minimal GOM servers for tests
+#[cfg(test)]
+pub(crate) mod test_server {
+    use super::*;
+
+    /// Starts a server that accepts one connection and never replies, returning its URL
+    pub(crate) fn silent() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            while socket.read().is_ok() {}
+        });
+        url
+    }
+
+    /// Starts a server that accepts one connection and closes it after the first message, returning its URL
+    pub(crate) fn closing() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            if socket.read().is_ok() {
+                let _ = socket.close(None);
+                while socket.read().is_ok() {}
+            }
+        });
+        url
+    }
+
+    /// Starts a server that answers requests with `handler(command, params)`, returning its URL.
+    ///
+    /// An `Err` from the handler is sent as an error message with that exception type. The server reads `batch` requests before replying to them in reverse order, so tests can
+    /// check that replies are matched to requests by their id.
+    pub(crate) fn replying(batch: usize, handler: fn(i64, &enc::CdcDict) -> Result<enc::CdcValue, &'static str>) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            let mut encoder = CdcEncoder::new();
+            loop {
+                let mut replies = Vec::new();
+                while replies.len() < batch {
+                    let Ok(msg) = socket.read() else { return };
+                    let mut request = encoder.decode_value(&mut msg.into_data().as_ref()).unwrap().expect_map();
+                    let command = request.remove(connection::attribute::VALUE).unwrap().expect_int();
+                    let params = request.remove(connection::attribute::PARAMS).unwrap().expect_map();
+                    let mut reply = enc::CdcDict::new();
+                    reply.insert(connection::attribute::ID.into(), request.remove(connection::attribute::ID).unwrap());
+                    match handler(command, &params) {
+                        Ok(value) => {
+                            reply.insert(connection::attribute::TYPE.into(), enc::CdcValue::STRING(connection::attribute::types::REPLY.into()));
+                            reply.insert(connection::attribute::VALUE.into(), value);
+                        }
+                        Err(error_type) => {
+                            reply.insert(connection::attribute::TYPE.into(), enc::CdcValue::STRING(connection::attribute::types::ERROR.into()));
+                            reply.insert(connection::attribute::ERROR.into(), enc::CdcValue::STRING(error_type.into()));
+                            reply.insert(connection::attribute::DESCRIPTION.into(), enc::CdcValue::STRING(String::new()));
+                            reply.insert(connection::attribute::CODE.into(), enc::CdcValue::INTEGER(0));
+                            reply.insert(connection::attribute::LOG.into(), enc::CdcValue::STRING(String::new()));
+                            reply.insert(connection::attribute::VALUE.into(), enc::CdcValue::BLOB(Vec::new()));
+                        }
+                    }
+                    replies.push(reply);
+                }
+                for reply in replies.into_iter().rev() {
+                    let bytes = Bytes::from(encoder.encode(enc::CdcValue::MAP(reply)));
+                    if socket.send(Message::Binary(bytes)).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        url
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_tls_config() {
+        assert!(TlsConfig::default().client_config().is_ok());
+        let insecure = TlsConfig { danger_accept_invalid_certs: true, ..TlsConfig::default() };
+        assert!(insecure.client_config().is_ok());
+
+        let invalid = TlsConfig::default().with_root_certificate_pem(b"-----BEGIN CERTIFICATE-----\nnot base64!\n-----END CERTIFICATE-----\n");
+        assert!(invalid.client_config().is_err());
+        assert!(TlsConfig::default().with_root_certificate_file("/nonexistent/root.pem").is_err());
+    }
+
+    #[test]
+    fn test_last_reply_bytes() {
+        let mut conn = Connection::init(&test_server::replying(1, |command, _| Ok(enc::CdcValue::INTEGER(command))), String::new()).unwrap();
+        conn.request(Request::LEN, ()).unwrap();
+        assert_eq!(conn.last_reply_bytes(), None);
+
+        conn.set_keep_last_reply(true);
+        conn.request(Request::LEN, ()).unwrap();
+        let mut bytes = conn.last_reply_bytes().unwrap();
+        let reply = CdcEncoder::new().decode_value(&mut bytes).unwrap().expect_map();
+        assert_eq!(reply[connection::attribute::VALUE], enc::CdcValue::INTEGER(Request::LEN as i64));
+
+        conn.set_keep_last_reply(false);
+        assert_eq!(conn.last_reply_bytes(), None);
+    }
+
+    #[test]
+    fn test_request_with_id() {
+        let mut conn = Connection::init(&test_server::replying(1, |command, _| Ok(enc::CdcValue::INTEGER(command))), String::new()).unwrap();
+        let (first_id, first) = conn.request_with_id(Request::LEN, ());
+        let (second_id, second) = conn.request_with_id(Request::REPR, ());
+        assert_eq!(first.unwrap(), enc::CdcValue::INTEGER(Request::LEN as i64));
+        assert_eq!(second.unwrap(), enc::CdcValue::INTEGER(Request::REPR as i64));
+        assert_ne!(first_id, second_id);
+        assert!(conn.pending.is_empty());
+    }
+
+    #[test]
+    fn test_request_batch_matches_replies_by_id() {
+        let url = test_server::replying(3, |_, params| Ok(params.get("name").cloned().unwrap_or(enc::CdcValue::NONE)));
+        let mut conn = Connection::init(&url, String::new()).unwrap();
+        let requests = ["a", "b", "c"]
+            .iter()
+            .map(|name| (Request::GETATTR, [("name", enc::CdcValue::STRING(name.to_string()))].into_params()))
+            .collect();
+        let results: Vec<enc::CdcValue> = conn.request_batch(requests).into_iter().map(Result::unwrap).collect();
+        assert_eq!(results, vec![
+            enc::CdcValue::STRING("a".to_string()),
+            enc::CdcValue::STRING("b".to_string()),
+            enc::CdcValue::STRING("c".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_error_reply_value_is_err() {
+        let error = crate::CdcError {
+            id: "error_id".to_string(),
+            text: "Command failed".to_string(),
+            line: 3,
+        };
+        let reply = connection::reply::Reply::REPLY(enc::CdcValue::ERROR(error.clone()));
+        match reply.into_result() {
+            Err(ConnectionError::CdcError(err)) => assert_eq!(err, error),
+            other => panic!("Expected CdcError, found {:?}", other),
+        }
+
+        let reply = connection::reply::Reply::REPLY(enc::CdcValue::INTEGER(1));
+        assert_eq!(reply.into_result().unwrap(), enc::CdcValue::INTEGER(1));
+    }
+
+    #[test]
+    fn test_default_stage_resolution() {
+        let mut conn = Connection::init(&test_server::silent(), String::new()).unwrap();
+        let item = crate::Item::new("item".to_string(), 1, crate::CURRENT_STAGE);
+        assert_eq!(conn.item_map(&item).get("stage"), Some(&enc::CdcValue::INTEGER(-1)));
+
+        conn.set_default_stage(crate::Stage::Index(4));
+        assert_eq!(conn.resolve_stage(crate::Stage::Current), crate::Stage::Index(4));
+        assert_eq!(conn.resolve_stage(crate::Stage::Index(2)), crate::Stage::Index(2));
+        assert_eq!(conn.item_stage(&item), 4);
+        assert_eq!(conn.item_map(&item).get("stage"), Some(&enc::CdcValue::INTEGER(4)));
+        let staged = crate::Item::new("item".to_string(), 1, 2);
+        assert_eq!(conn.item_map(&staged).get("stage"), Some(&enc::CdcValue::INTEGER(2)));
+    }
+
+    #[test]
+    fn test_item_maps_are_reused() {
+        let mut conn = Connection::init(&test_server::replying(1, |_, _| Ok(enc::CdcValue::BOOL(false))), String::new()).unwrap();
+        let item = crate::Item::new("item".to_string(), 1, 0);
+        let other = crate::Item::new("other".to_string(), 1, 0);
+        let params = crate::params::CompareParams { item: conn.item_map(&item), other: conn.item_map(&other) };
+        conn.request(Request::LESS, params).unwrap();
+        assert_eq!(conn.item_scratch.len(), 2);
+        let params = crate::params::DocParams { object: conn.item_map(&item) };
+        conn.request(Request::DOC, params).unwrap();
+        assert_eq!(conn.item_scratch.len(), 2);
+        assert!(conn.item_scratch.iter().all(|map| map.capacity() >= 3));
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_requests() {
+        let url = test_server::replying(2, |_, params| Ok(params.get("name").cloned().unwrap_or(enc::CdcValue::NONE)));
+        let conn = AsyncConnection::new(Connection::init(&url, String::new()).unwrap());
+        let first = conn.request_async(Request::GETATTR, [("name", enc::CdcValue::STRING("first".to_string()))], RequestPriority::Low);
+        let second = std::thread::scope(|scope| {
+            scope.spawn(|| conn.request(Request::GETATTR, [("name", enc::CdcValue::STRING("second".to_string()))])).join().unwrap()
+        });
+        assert_eq!(first.recv().unwrap().unwrap(), enc::CdcValue::STRING("first".to_string()));
+        assert_eq!(second.unwrap(), enc::CdcValue::STRING("second".to_string()));
+
+        // The idle reader thread wakes up for new requests
+        std::thread::sleep(BACKGROUND_POLL_INTERVAL * 4);
+        let third = conn.request_async(Request::GETATTR, [("name", enc::CdcValue::STRING("third".to_string()))], RequestPriority::Normal);
+        let fourth = conn.request_async(Request::GETATTR, [("name", enc::CdcValue::STRING("fourth".to_string()))], RequestPriority::Normal);
+        assert_eq!(third.recv().unwrap().unwrap(), enc::CdcValue::STRING("third".to_string()));
+        assert_eq!(fourth.recv().unwrap().unwrap(), enc::CdcValue::STRING("fourth".to_string()));
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_reader_reports_why_it_stopped() {
+        let conn = AsyncConnection::new(Connection::init(&test_server::closing(), String::new()).unwrap());
+        let waiting = conn.request_async(Request::REPR, (), RequestPriority::Normal);
+        assert!(matches!(waiting.recv().unwrap(), Err(ConnectionError::ConnectionClosed(reason)) if reason == "closed by the server"));
+        // Requests queued after the reader thread stopped report the same reason
+        std::thread::sleep(BACKGROUND_POLL_INTERVAL * 4);
+        assert!(matches!(conn.request(Request::REPR, ()), Err(ConnectionError::ConnectionClosed(reason)) if reason == "closed by the server"));
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_schedule_order() {
+        let mut schedule = Schedule::default();
+        let mut receivers = Vec::new();
+        for (priority, command) in [
+            (RequestPriority::Low, Request::LEN),
+            (RequestPriority::Normal, Request::GETATTR),
+            (RequestPriority::High, Request::REPR),
+            (RequestPriority::Normal, Request::SETATTR),
+        ] {
+            let (reply, receiver) = oneshot::channel();
+            receivers.push(receiver);
+            schedule.push(priority, QueuedRequest { priority, command, params: enc::CdcDict::new(), reply });
+        }
+        let order: Vec<Request> = std::iter::from_fn(|| schedule.pop()).map(|queued| queued.command).collect();
+        assert_eq!(order, [Request::REPR, Request::GETATTR, Request::SETATTR, Request::LEN]);
+        assert!(schedule.is_empty());
+    }
+
+    #[test]
+    fn test_call_result_send_failure() {
+        fn callback(_args: enc::CdcList, _kwargs: enc::CdcDict) -> enc::CdcValue {
+            enc::CdcValue::NONE
+        }
+        let mut conn = Connection::init(&test_server::silent(), String::new()).unwrap();
+        conn.encoder.encode(enc::CdcValue::CALLABLE(callback));
+        let callable_id = conn.registered_callables()[0].id;
+
+        // The server refers to a callable by its id only
+        let mut call = vec![enc::CdcType::MAP as u8];
+        call.extend(&4i64.to_le_bytes());
+        let mut entry = |key: &str, value: Vec<u8>| {
+            enc::Codec::encode_string(&mut call, &key.to_string());
+            call.extend(value);
+        };
+        entry(connection::attribute::TYPE, conn.encoder.encode(enc::CdcValue::STRING(connection::attribute::types::CALL.into())));
+        let mut value = vec![enc::CdcType::CALLABLE as u8];
+        enc::Codec::encode_string(&mut value, &callable_id.to_string());
+        entry(connection::attribute::VALUE, value);
+        entry(connection::attribute::ARGS, conn.encoder.encode(enc::CdcValue::LIST(vec![])));
+        entry(connection::attribute::KWARGS, conn.encoder.encode(enc::CdcValue::MAP(enc::CdcDict::new())));
+
+        conn.socket.close(None).unwrap();
+        let result = conn.handle_message(Message::Binary(Bytes::from(call)), Uuid::nil());
+        assert!(matches!(result, Err(ConnectionError::SendFailed)));
+    }
+
+    #[test]
+    fn test_result_message_is_reply() {
+        let mut conn = Connection::init(&test_server::silent(), String::new()).unwrap();
+        let request_id = conn.send_request(Request::RUNAPI, enc::CdcDict::new()).unwrap();
+        let mut result = enc::CdcDict::new();
+        result.insert(connection::attribute::TYPE.into(), enc::CdcValue::STRING(connection::attribute::types::RESULT.into()));
+        result.insert(connection::attribute::ID.into(), enc::CdcValue::STRING(request_id.to_string()));
+        result.insert(connection::attribute::VALUE.into(), enc::CdcValue::INTEGER(7));
+        let message = Message::Binary(Bytes::from(conn.encoder.encode(enc::CdcValue::MAP(result))));
+        conn.handle_message(message, Uuid::nil()).unwrap();
+        let reply = conn.wait_for_reply(request_id, None, None).unwrap();
+        assert_eq!(conn.finish_request(Request::RUNAPI, request_id, reply).unwrap(), enc::CdcValue::INTEGER(7));
+    }
+
+    #[test]
+    fn test_unknown_message_type_is_ignored() {
+        let mut conn = Connection::init(&test_server::silent(), String::new()).unwrap();
+        let mut message = enc::CdcDict::new();
+        message.insert(connection::attribute::TYPE.into(), enc::CdcValue::STRING("notification".into()));
+        message.insert(connection::attribute::VALUE.into(), enc::CdcValue::INTEGER(1));
+        let message = Message::Binary(Bytes::from(conn.encoder.encode(enc::CdcValue::MAP(message))));
+        assert!(conn.handle_message(message, Uuid::nil()).is_ok());
+        assert!(conn.replies.is_empty());
+    }
+
+    #[test]
+    fn test_socket_config() {
+        let conn = Connection::init(&test_server::silent(), String::new()).unwrap();
+        assert!(conn.tcp_stream().unwrap().nodelay().unwrap());
+        let config = SocketConfig { nodelay: false, recv_buffer_size: Some(1 << 16), send_buffer_size: Some(1 << 16) };
+        #[cfg(unix)]
+        conn.set_socket_config(&config).unwrap();
+        #[cfg(not(unix))]
+        assert!(conn.set_socket_config(&config).is_err());
+        assert!(!conn.tcp_stream().unwrap().nodelay().unwrap());
+    }
+
+    #[test]
+    fn test_run_api() {
+        let url = test_server::replying(1, |command, params| match command {
+            command if command == Request::RUNAPI as i64 => Ok(params["code"].clone()),
+            _ => Err(connection::error::PYTHON),
+        });
+        let mut conn = Connection::init(&url, String::new()).unwrap();
+        assert_eq!(conn.run_api("result = 1", enc::CdcDict::new()).unwrap(), enc::CdcValue::STRING("result = 1".to_string()));
+    }
+
+    #[test]
+    fn test_init_timeout_handshake() {
+        // Accepts TCP connections but never completes the WebSocket handshake
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let start = Instant::now();
+        let result = Connection::init_timeout(&url, String::new(), Duration::from_millis(100));
+        assert!(matches!(&result, Err(ConnectionError::Connect(reason)) if *reason == format!("{}: timed out", url)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        drop(listener);
+
+        let conn = Connection::init_timeout(&test_server::silent(), String::new(), Duration::from_secs(5));
+        assert!(conn.is_ok());
+    }
+
+    #[test]
+    fn test_init_error() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let Err(ConnectionError::Connect(reason)) = Connection::init(&url, String::new()) else { panic!("Connecting to a closed port succeeded") };
+        assert!(reason.starts_with(&url), "{}", reason);
+        assert!(matches!(Connection::init("not a url", String::new()), Err(ConnectionError::Connect(_))));
+    }
+
+    #[test]
+    fn test_register_timeout() {
+        let mut conn = Connection::init(&test_server::silent(), String::new()).unwrap();
+        let result = conn.register_timeout("interpreter", "file", Duration::from_millis(50));
+        assert!(matches!(result, Err(ConnectionError::Timeout)));
+    }
+
+    #[test]
+    fn test_item_request_detects_stale_items() {
+        let url = test_server::replying(1, |_, _| Err(connection::error::INDEX));
+        let mut conn = Connection::init(&url, String::new()).unwrap();
+        let item = crate::Item::new("old".to_string(), 0, 0);
+        assert!(matches!(conn.item_request(&item, Request::GETATTR, ()), Err(ConnectionError::StaleItem)));
+    }
+
+    #[test]
+    fn test_item_request_keeps_attribute_errors() {
+        let url = test_server::replying(1, |command, _| match command {
+            command if command == Request::REPR as i64 => Ok(enc::CdcValue::STRING("item".to_string())),
+            _ => Err(connection::error::ATTRIBUTE),
+        });
+        let mut conn = Connection::init(&url, String::new()).unwrap();
+        let item = crate::Item::new("item".to_string(), 0, 0);
+        assert!(matches!(conn.item_request(&item, Request::GETATTR, ()), Err(ConnectionError::Attribute(_))));
+    }
+
+    #[test]
+    fn test_server_error_details() {
+        let reply = connection::reply::Error {
+            error_type: connection::error::PYTHON.to_string(),
+            description: "name 'x' is not defined".to_string(),
+            code: 3,
+            log: "Traceback (most recent call last):".to_string(),
+            value: Bytes::new(),
+        };
+        let err = ConnectionError::from(reply);
+        let ConnectionError::Server(details) = &err else { panic!("unexpected error {:?}", err) };
+        assert_eq!(details.code, 3);
+        assert_eq!(
+            err.to_string(),
+            "Server error: Tom::GScript::PythonException (code 3): name 'x' is not defined\nTraceback (most recent call last):"
+        );
+    }
+
+    #[test]
+    fn test_translation_cache_eviction() {
+        let mut cache = TranslationCache { capacity: 2, ..TranslationCache::default() };
+        cache.insert("a", None, "A");
+        cache.insert("b", Some("id"), "B");
+        assert_eq!(cache.get("a", None).as_deref(), Some("A"));
+        assert_eq!(cache.get("b", None), None);
+        cache.insert("c", None, "C");
+        assert_eq!(cache.get("b", Some("id")), None);
+        assert_eq!(cache.get("a", None).as_deref(), Some("A"));
+        assert_eq!(cache.get("c", None).as_deref(), Some("C"));
+
+        // Updating a cached text doesn't evict another one
+        cache.insert("a", None, "A2");
+        assert_eq!(cache.get("c", None).as_deref(), Some("C"));
+        assert_eq!(cache.get("a", None).as_deref(), Some("A2"));
+        assert_eq!((cache.entries.len(), cache.order.len()), (2, 2));
+    }
+
+    #[test]
+    fn test_set_line() {
+        let url = test_server::replying(1, |command, params| match command {
+            command if command == Request::LINE as i64 => Ok(params["line"].clone()),
+            _ => Err(connection::error::PYTHON),
+        });
+        let mut conn = Connection::init(&url, String::new()).unwrap();
+        assert!(matches!(conn.request(Request::GET, ()), Err(ConnectionError::Server(ServerError { line: None, .. }))));
+        conn.set_line("main.rs", 42).unwrap();
+        assert_eq!(conn.line(), Some(("main.rs", 42)));
+        let err = conn.request(Request::GET, ()).unwrap_err();
+        assert!(err.to_string().ends_with(" at main.rs:42"), "{}", err);
+    }
+
+    #[test]
+    fn test_pump() {
+        let url = test_server::replying(1, |_, _| Ok(enc::CdcValue::INTEGER(5)));
+        let mut conn = Connection::init(&url, String::new()).unwrap();
+        assert_eq!(conn.pump(10).unwrap(), 0);
+
+        let request_id = conn.send_request(Request::REPR, enc::CdcDict::new()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while conn.buffered_reply_count() == 0 {
+            assert!(Instant::now() < deadline, "The reply never arrived");
+            conn.pump(10).unwrap();
+        }
+        assert!(conn.replies.contains_key(&request_id));
+        assert_eq!(conn.request(Request::REPR, enc::CdcDict::new()).unwrap(), enc::CdcValue::INTEGER(5));
+    }
+
+    #[test]
+    fn test_idempotent_failure() {
+        let mut conn = Connection::init(&test_server::closing(), String::new()).unwrap();
+        assert!(matches!(conn.request_idempotent("key", Request::REPR, (), None, None), Err(ConnectionError::ConnectionClosed(_))));
+        // Only timed out and cancelled requests are resumed by a retry
+        assert!(conn.idempotent.is_empty());
+        assert!(conn.pending.is_empty());
+    }
+
+    #[test]
+    fn test_closed_connection() {
+        let mut conn = Connection::init(&test_server::closing(), String::new()).unwrap();
+        assert!(matches!(conn.request(Request::REPR, ()), Err(ConnectionError::ConnectionClosed(_))));
+        assert!(matches!(conn.pump(10), Err(ConnectionError::ConnectionClosed(_))));
+    }
+
+    #[test]
+    fn test_envelope() {
+        let mut reply = enc::CdcDict::new();
+        reply.insert(connection::attribute::TYPE.into(), enc::CdcValue::STRING(connection::attribute::types::REPLY.into()));
+        reply.insert(connection::attribute::ID.into(), enc::CdcValue::STRING("id".into()));
+        reply.insert(connection::attribute::VALUE.into(), enc::CdcValue::INTEGER(7));
+        let envelope = Envelope::decode(&enc::Codec::new().encode(&enc::CdcValue::MAP(reply.clone()))).unwrap();
+        assert_eq!(envelope.msg_type, connection::attribute::types::REPLY);
+        assert_eq!(envelope.id.as_deref(), Some("id"));
+        assert!(matches!(envelope.kind, EnvelopeKind::Reply(enc::CdcValue::INTEGER(7))));
+
+        #[cfg(feature = "ordered-maps")]
+        {
+            let ordered = enc::CdcValue::ORDERED_MAP(reply.clone().into_iter().collect());
+            assert!(matches!(Envelope::from_value(ordered).unwrap().kind, EnvelopeKind::Reply(enc::CdcValue::INTEGER(7))));
         }
-        let result = self.replies.remove(&request_id).expect("Ended receiving loop before the message was received!");
-        match result{
-            connection::reply::Reply::ERROR(err) => Err(ConnectionError::from(err)),
-            connection::reply::Reply::REPLY(value) => Ok(value),
+
+        reply.remove(connection::attribute::VALUE);
+        assert!(matches!(Envelope::from_value(enc::CdcValue::MAP(reply)), Err(enc::DecodeError::InvalidEnvelope { key: connection::attribute::VALUE })));
+
+        let mut error = enc::CdcDict::new();
+        error.insert(connection::attribute::TYPE.into(), enc::CdcValue::STRING(connection::attribute::types::ERROR.into()));
+        error.insert(connection::attribute::ERROR.into(), enc::CdcValue::STRING(connection::error::INDEX.into()));
+        error.insert(connection::attribute::DESCRIPTION.into(), enc::CdcValue::STRING("Index out of range".into()));
+        error.insert(connection::attribute::CODE.into(), enc::CdcValue::INTEGER(3));
+        error.insert(connection::attribute::LOG.into(), enc::CdcValue::STRING(String::new()));
+        error.insert(connection::attribute::VALUE.into(), enc::CdcValue::BLOB(vec![1, 2]));
+        let envelope = Envelope::from_value(enc::CdcValue::MAP(error.clone())).unwrap();
+        assert_eq!(envelope.id, None);
+        assert!(matches!(envelope.kind, EnvelopeKind::Error(EnvelopeError { code: 3, .. })));
+        error.insert(connection::attribute::CODE.into(), enc::CdcValue::STRING("3".into()));
+        assert!(matches!(Envelope::from_value(enc::CdcValue::MAP(error)), Err(enc::DecodeError::InvalidEnvelope { key: connection::attribute::CODE })));
+
+        assert!(matches!(Envelope::from_value(enc::CdcValue::INTEGER(1)), Err(enc::DecodeError::InvalidEnvelope { key: connection::attribute::TYPE })));
+
+        let call = enc::CdcDict::from([
+            (connection::attribute::TYPE.to_string(), enc::CdcValue::STRING(connection::attribute::types::CALL.to_string())),
+            (connection::attribute::VALUE.to_string(), enc::CdcValue::STRING("not callable".to_string())),
+            (connection::attribute::ARGS.to_string(), enc::CdcValue::LIST(Vec::new())),
+            (connection::attribute::KWARGS.to_string(), enc::CdcValue::MAP(enc::CdcDict::new())),
+        ]);
+        assert!(matches!(Envelope::from_value(enc::CdcValue::MAP(call)), Err(enc::DecodeError::InvalidEnvelope { key: connection::attribute::VALUE })));
+    }
+
+    #[test]
+    fn test_send_encode_error() {
+        let mut conn = Connection::init(&test_server::silent(), String::new()).unwrap();
+        let deep = (0..enc::DEFAULT_MAX_DEPTH).fold(enc::CdcValue::NONE, |value, _| enc::CdcValue::LIST(vec![value]));
+        let result = conn.request(Request::TEST, [("value", deep)]);
+        assert!(matches!(result, Err(ConnectionError::CdcError(err)) if err.id == "EncodeError"));
+        assert!(conn.pending.is_empty());
+    }
+
+    #[test]
+    fn test_malformed_message() {
+        use tungstenite::protocol::Role;
+        let mut server = WebSocket::from_raw_socket(Pipe { input: Default::default(), output: Vec::new() }, Role::Server, None);
+        server.send(Message::Binary(Bytes::from(CdcEncoder::new().encode(enc::CdcValue::INTEGER(1))))).unwrap();
+        let input = std::io::Cursor::new(server.into_inner().output);
+        let socket = WebSocket::from_raw_socket(Pipe { input, output: Vec::new() }, Role::Client, None);
+        let mut conn = Connection::from_socket(socket, String::new());
+        assert!(matches!(conn.request(Request::LEN, ()), Err(ConnectionError::CdcError(_))));
+    }
+
+    /// An in-memory stream that reads scripted server frames and records what the client writes
+    struct Pipe {
+        input: std::io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+    impl std::io::Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+    impl std::io::Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
         }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    impl Transport for Pipe {}
+
+    #[test]
+    fn test_from_socket() {
+        use tungstenite::protocol::Role;
+        let request_id = Uuid::from_u128(3);
+        let reply = enc::CdcDict::from([
+            (connection::attribute::TYPE.to_string(), enc::CdcValue::STRING(connection::attribute::types::REPLY.to_string())),
+            (connection::attribute::ID.to_string(), enc::CdcValue::STRING(request_id.to_string())),
+            (connection::attribute::VALUE.to_string(), enc::CdcValue::INTEGER(5)),
+        ]);
+        let mut server = WebSocket::from_raw_socket(Pipe { input: Default::default(), output: Vec::new() }, Role::Server, None);
+        server.send(Message::Binary(Bytes::from(CdcEncoder::new().encode(enc::CdcValue::MAP(reply))))).unwrap();
+        let input = std::io::Cursor::new(server.into_inner().output);
+
+        let socket = WebSocket::from_raw_socket(Pipe { input, output: Vec::new() }, Role::Client, None);
+        let mut conn = Connection::from_socket(socket, "key".to_string());
+        conn.set_request_id_generator(move || request_id);
+        assert_eq!(conn.request(Request::LEN, ()).unwrap(), enc::CdcValue::INTEGER(5));
+        assert!(!conn.socket.get_ref().output.is_empty());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_close() {
+        let url = test_server::replying(1, |command, params| match command {
+            command if command == Request::RELEASE as i64 && params["id"] == enc::CdcValue::STRING("interpreter".to_string()) => Ok(enc::CdcValue::NONE),
+            command if command == Request::RELEASE as i64 => Err("RuntimeError"),
+            _ => Ok(enc::CdcValue::NONE),
+        });
+        let mut conn = Connection::init(&url, String::new()).unwrap();
+        conn.register("interpreter", "script.py").unwrap();
+        assert!(conn.close().is_ok());
+
+        let conn = Connection::init(&test_server::silent(), String::new()).unwrap();
+        assert!(matches!(conn.close(), Err(ConnectionError::Timeout)));
+    }
+
+    #[test]
+    fn test_encode_request() {
+        let mut conn = Connection::init(&test_server::silent(), "key".to_string()).unwrap();
+        conn.set_request_id_generator(|| Uuid::from_u128(7));
+        let params = enc::CdcDict::from([("name".to_string(), enc::CdcValue::STRING("value".to_string()))]);
+        let (request_id, bytes) = conn.encode_request(Request::GETATTR, params.clone());
+        assert_eq!(request_id, Uuid::from_u128(7));
+
+        let mut message = enc::decode_complete(&bytes).unwrap().expect_map();
+        assert_eq!(message.remove(connection::attribute::ID), Some(enc::CdcValue::STRING(request_id.to_string())));
+        assert_eq!(message.remove(connection::attribute::APIKEY), Some(enc::CdcValue::STRING("key".to_string())));
+        assert_eq!(message.remove(connection::attribute::VALUE), Some(enc::CdcValue::INTEGER(Request::GETATTR as i64)));
+        assert_eq!(message.remove(connection::attribute::PARAMS), Some(enc::CdcValue::MAP(params)));
+        assert!(conn.pending.is_empty());
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let url = test_server::replying(1, |_, params| {
+            let bits = params["capabilities"].clone().expect_int();
+            Ok(enc::CdcValue::MAP(enc::CdcDict::from([("capabilities".to_string(), enc::CdcValue::INTEGER(bits | 1 << 10))])))
+        });
+        let mut conn = Connection::init(&url, String::new()).unwrap();
+        assert_eq!(conn.server_capabilities(), Capabilities::NONE);
+        conn.set_negotiate_capabilities(true);
+        conn.register("interpreter", "script.py").unwrap();
+        assert_eq!(conn.server_capabilities(), Capabilities::client() | Capabilities::from_bits(1 << 10));
+        assert_eq!(conn.capabilities(), Capabilities::client());
+
+        let url = test_server::replying(1, |_, _| Ok(enc::CdcValue::NONE));
+        let mut conn = Connection::init(&url, String::new()).unwrap();
+        conn.set_negotiate_capabilities(true);
+        conn.register("interpreter", "script.py").unwrap();
+        assert_eq!(conn.capabilities(), Capabilities::NONE);
+
+        // Without opting in the request keeps its original parameters
+        let url = test_server::replying(1, |_, params| match params.contains_key("capabilities") {
+            true => Err("Unexpected capabilities"),
+            false => Ok(enc::CdcValue::NONE),
+        });
+        let mut conn = Connection::init(&url, String::new()).unwrap();
+        conn.register("interpreter", "script.py").unwrap();
+        assert_eq!(conn.capabilities(), Capabilities::NONE);
+    }
+
+    #[test]
+    fn test_configuration() {
+        let url = test_server::replying(1, |_, params| match params["write"] {
+            enc::CdcValue::BOOL(true) => Ok(enc::CdcValue::NONE),
+            _ => Ok(enc::CdcValue::STRING(params["key"].clone().expect_string() + " value")),
+        });
+        let mut conn = Connection::init(&url, String::new()).unwrap();
+        assert_eq!(conn.get_configuration("language").unwrap(), enc::CdcValue::STRING("language value".to_string()));
+        assert!(conn.set_configuration("language", enc::CdcValue::STRING("en".to_string())).is_ok());
+    }
+
+    #[test]
+    fn test_run_protocol_test() {
+        let url = test_server::replying(1, |command, _| Ok(enc::CdcValue::INTEGER(command)));
+        let mut conn = Connection::init(&url, String::new()).unwrap();
+        for which in TestCase::ALL {
+            assert_eq!(conn.run_protocol_test(which).unwrap(), enc::CdcValue::INTEGER(which.request() as i64));
+        }
+    }
+
+    #[test]
+    fn test_request_id_generator() {
+        let url = test_server::replying(1, |_, _| Ok(enc::CdcValue::NONE));
+        let mut conn = Connection::init(&url, String::new()).unwrap();
+        let mut next = 0;
+        conn.set_request_id_generator(move || {
+            next += 1;
+            Uuid::from_u128(next)
+        });
+        let (first, result) = conn.request_with_id(Request::REPR, ());
+        assert!(result.is_ok());
+        let (second, result) = conn.request_with_id(Request::REPR, ());
+        assert!(result.is_ok());
+        assert_eq!((first, second), (Uuid::from_u128(1), Uuid::from_u128(2)));
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        let mut conn = Connection::init(&test_server::silent(), String::new()).unwrap();
+        let result = conn.request_cancellable(Request::LEN, (), Some(Duration::from_millis(100)), None);
+        assert!(matches!(result, Err(ConnectionError::Timeout)));
+        assert_eq!(conn.abandoned.len(), 1);
+    }
+
+    #[test]
+    fn test_request_idempotent_retry() {
+        let mut conn = Connection::init(&test_server::silent(), String::new()).unwrap();
+        let timeout = Some(Duration::from_millis(50));
+        let result = conn.request_idempotent("key", Request::COMMAND, (), timeout, None);
+        assert!(matches!(result, Err(ConnectionError::Timeout)));
+        let request_id = conn.idempotent["key"];
+        assert!(conn.pending.contains(&request_id) && conn.abandoned.is_empty());
+
+        // The late reply is kept for the retry, which doesn't send the request again
+        let mut reply = enc::CdcDict::new();
+        reply.insert(connection::attribute::TYPE.into(), enc::CdcValue::STRING(connection::attribute::types::REPLY.into()));
+        reply.insert(connection::attribute::ID.into(), enc::CdcValue::STRING(request_id.to_string()));
+        reply.insert(connection::attribute::VALUE.into(), enc::CdcValue::INTEGER(1));
+        let message = Message::Binary(Bytes::from(conn.encoder.encode(enc::CdcValue::MAP(reply))));
+        conn.handle_message(message, Uuid::nil()).unwrap();
+        let result = conn.request_idempotent("key", Request::COMMAND, (), timeout, None);
+        assert_eq!(result.unwrap(), enc::CdcValue::INTEGER(1));
+        assert!(conn.idempotent.is_empty() && conn.pending.is_empty());
+    }
+
+    #[test]
+    fn test_reply_buffer_limit() {
+        let mut conn = Connection::init(&test_server::silent(), String::new()).unwrap();
+        conn.set_max_buffered_replies(2);
+        let timeout = Some(Duration::from_millis(20));
+        let keys = ["first", "second", "third"];
+        let mut ids = Vec::new();
+        for key in keys {
+            assert!(matches!(conn.request_idempotent(key, Request::COMMAND, (), timeout, None), Err(ConnectionError::Timeout)));
+            ids.push(conn.idempotent[key]);
+        }
+        for (value, request_id) in ids.iter().enumerate() {
+            let mut reply = enc::CdcDict::new();
+            reply.insert(connection::attribute::TYPE.into(), enc::CdcValue::STRING(connection::attribute::types::REPLY.into()));
+            reply.insert(connection::attribute::ID.into(), enc::CdcValue::STRING(request_id.to_string()));
+            reply.insert(connection::attribute::VALUE.into(), enc::CdcValue::INTEGER(value as i64));
+            let message = Message::Binary(Bytes::from(conn.encoder.encode(enc::CdcValue::MAP(reply))));
+            conn.handle_message(message, Uuid::nil()).unwrap();
+        }
+        assert_eq!(conn.buffered_reply_count(), 2);
+        assert!(!conn.idempotent.contains_key("first"));
+        assert_eq!(conn.request_idempotent("third", Request::COMMAND, (), timeout, None).unwrap(), enc::CdcValue::INTEGER(2));
+        assert_eq!(conn.buffered_reply_count(), 1);
+    }
+
+    #[test]
+    fn test_request_cancelled() {
+        let mut conn = Connection::init(&test_server::silent(), String::new()).unwrap();
+        let cancel = std::sync::Arc::new(AtomicBool::new(false));
+        let flag = cancel.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            flag.store(true, Ordering::SeqCst);
+        });
+        let result = conn.request_cancellable(Request::LEN, (), None, Some(&cancel));
+        assert!(matches!(result, Err(ConnectionError::Cancelled)));
+    }
+
+    #[test]
+    fn test_to_params_shapes() {
+        let mut expected = enc::CdcDict::new();
+        expected.insert("name".to_string(), enc::CdcValue::STRING("value".to_string()));
+
+        let array = [("name", enc::CdcValue::STRING("value".to_string()))];
+        assert_eq!(array.to_params(), expected);
+        assert_eq!(array.into_params(), expected);
+        assert_eq!(vec![("name".to_string(), enc::CdcValue::STRING("value".to_string()))].into_params(), expected);
+        assert_eq!((&expected).into_params(), expected);
+        assert!(().to_params().is_empty());
+    }
+
+    #[test]
+    fn test_log_config_levels() {
+        let config = LogConfig::new().with_level(Request::GET, log::LevelFilter::Trace);
+        assert_eq!(config.level_for(Request::GET), log::LevelFilter::Trace);
+        assert_eq!(config.level_for(Request::COMMAND), log::LevelFilter::Debug);
+    }
+
+    #[test]
+    fn test_log_config_truncates_payload() {
+        let config = LogConfig { max_payload_len: 10, ..LogConfig::new() };
+        let formatted = config.format_payload(&enc::CdcValue::BLOB(vec![0u8; 1000]));
+        assert!(formatted.starts_with("BLOB([0, 0... "));
+        assert!(formatted.ends_with("bytes truncated)"));
+
+        let short = config.format_payload(&enc::CdcValue::NONE);
+        assert_eq!(short, "NONE");
+    }
+}
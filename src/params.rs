@@ -0,0 +1,234 @@
+use crate::encoding::{CdcDict, CdcList, CdcValue};
+use crate::network::ToParams;
+
+/// The parameter keys the server expects, shared by all requests
+pub(crate) mod keys {
+    pub const ARGS: &str = "args";
+    pub const CAPABILITIES: &str = "capabilities";
+    pub const CODE: &str = "code";
+    pub const COMMAND: &str = "command";
+    pub const CONDITION: &str = "condition";
+    pub const EXPRESSION: &str = "expression";
+    pub const FILE: &str = "file";
+    pub const ID: &str = "id";
+    pub const INDEX: &str = "index";
+    pub const ITEM: &str = "item";
+    pub const KEY: &str = "key";
+    pub const KWARGS: &str = "kwargs";
+    pub const LINE: &str = "line";
+    pub const NAME: &str = "name";
+    pub const OBJECT: &str = "object";
+    pub const OTHER: &str = "other";
+    pub const PARAMS: &str = "params";
+    pub const SELECTION: &str = "selection";
+    pub const STAGE: &str = "stage";
+    pub const TEXT: &str = "text";
+    pub const VALUE: &str = "value";
+    pub const WRITE: &str = "write";
+}
+
+/// Inserts `value` under `key`, leaving the key out if there is no value.
+///
+/// This is the convention for all optional parameters: `None` means the argument was not given
+/// and the server applies its default, while `Some(CdcValue::NONE)` sends an explicit null.
+pub fn insert_optional(map: &mut CdcDict, key: &str, value: Option<CdcValue>) {
+    if let Some(value) = value {
+        map.insert(key.to_string(), value);
+    }
+}
+
+/// Implements `ToParams` for a parameter struct from its fields and their keys.
+///
+/// Fields listed under `optional` are `Option`s inserted with `insert_optional`.
+macro_rules! impl_to_params {
+    ($name:ident $(<$lt:lifetime>)? { $($field:ident => $key:ident),* $(,)? } $(optional { $($opt_field:ident => $opt_key:ident),* $(,)? })?) => {
+        impl $(<$lt>)? ToParams for $name $(<$lt>)? {
+            fn to_params(&self) -> CdcDict {
+                self.clone().into_params()
+            }
+            fn into_params(self) -> CdcDict {
+                let mut params = CdcDict::new();
+                $(params.insert(keys::$key.to_string(), self.$field.into());)*
+                $($(insert_optional(&mut params, keys::$opt_key, self.$opt_field.map(Into::into));)*)?
+                params
+            }
+        }
+    };
+}
+
+/// Parameters of requests that only refer to an item, e.g. `TOKENS`, `LEN` and `REPR`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ItemParams {
+    /// The item map, see `Connection::item_map`
+    pub item: CdcDict,
+}
+impl_to_params!(ItemParams { item => ITEM });
+
+/// Parameters of a `GET` request
+#[derive(Debug, Clone, PartialEq)]
+pub struct GetParams<'a> {
+    pub item: CdcDict,
+    pub name: &'a str,
+    pub index: Option<i64>,
+}
+impl_to_params!(GetParams<'a> { item => ITEM, name => NAME } optional { index => INDEX });
+
+/// Parameters of an `INDEX` request
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexParams {
+    pub item: CdcDict,
+    pub index: i64,
+}
+impl_to_params!(IndexParams { item => ITEM, index => INDEX });
+
+/// Parameters of a `FILTER` request
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterParams<'a> {
+    pub item: CdcDict,
+    pub expression: &'a str,
+    pub condition: Option<&'a str>,
+}
+impl_to_params!(FilterParams<'a> { item => ITEM, expression => EXPRESSION } optional { condition => CONDITION });
+
+/// Parameters of the `LESS` and `EQUAL` requests
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompareParams {
+    pub item: CdcDict,
+    pub other: CdcDict,
+}
+impl_to_params!(CompareParams { item => ITEM, other => OTHER });
+
+/// Parameters of a `GETATTR` request
+#[derive(Debug, Clone, PartialEq)]
+pub struct GetAttrParams<'a> {
+    pub item: CdcDict,
+    pub name: &'a str,
+    /// The resolved stage of the item on the wire
+    pub stage: i64,
+}
+impl_to_params!(GetAttrParams<'a> { item => ITEM, name => NAME, stage => STAGE });
+
+/// Parameters of a `SETATTR` request
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetAttrParams<'a> {
+    pub item: CdcDict,
+    pub name: &'a str,
+    pub value: CdcValue,
+}
+impl_to_params!(SetAttrParams<'a> { item => ITEM, name => NAME, value => VALUE });
+
+/// Parameters of a `KEY` request
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyParams<'a> {
+    pub item: CdcDict,
+    pub name: &'a str,
+}
+impl_to_params!(KeyParams<'a> { item => ITEM, name => NAME });
+
+/// Parameters of a `DOC` request
+#[derive(Debug, Clone, PartialEq)]
+pub struct DocParams {
+    pub object: CdcDict,
+}
+impl_to_params!(DocParams { object => OBJECT });
+
+/// Parameters of a `COMMAND` request
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandParams<'a> {
+    pub command: &'a str,
+    pub args: CdcList,
+    pub kwargs: CdcDict,
+    /// The resolved stage to execute the command in
+    pub stage: Option<i64>,
+    /// Items to execute the command on instead of the current selection
+    pub selection: Option<CdcList>,
+}
+impl_to_params!(CommandParams<'a> { command => COMMAND, args => ARGS, kwargs => KWARGS } optional { stage => STAGE, selection => SELECTION });
+
+/// Parameters of a `TRANSLATE` request
+#[derive(Debug, Clone, PartialEq)]
+pub struct TranslateParams<'a> {
+    pub text: &'a str,
+    /// The translation id
+    pub id: Option<&'a str>,
+}
+impl_to_params!(TranslateParams<'a> { text => TEXT } optional { id => ID });
+
+/// Parameters of a `QUERY` request
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryParams<'a> {
+    pub expression: &'a str,
+}
+impl_to_params!(QueryParams<'a> { expression => EXPRESSION });
+
+/// Parameters of a `LINE` request
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineParams<'a> {
+    pub file: &'a str,
+    pub line: i64,
+}
+impl_to_params!(LineParams<'a> { file => FILE, line => LINE });
+
+/// Parameters of a `RUNAPI` request
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunApiParams<'a> {
+    pub code: &'a str,
+    pub params: CdcDict,
+}
+impl_to_params!(RunApiParams<'a> { code => CODE, params => PARAMS });
+
+/// Parameters of a `CONFIGURATION` request, which reads a setting or writes `value` to it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigurationParams<'a> {
+    pub key: &'a str,
+    pub write: bool,
+    pub value: Option<CdcValue>,
+}
+impl_to_params!(ConfigurationParams<'a> { key => KEY, write => WRITE } optional { value => VALUE });
+
+/// Parameters of a `RELEASE` request
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReleaseParams<'a> {
+    /// The interpreter id
+    pub id: &'a str,
+}
+impl_to_params!(ReleaseParams<'a> { id => ID });
+
+/// Parameters of a `REGISTER` request
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterParams<'a> {
+    /// The interpreter id
+    pub id: &'a str,
+    pub file: &'a str,
+    /// The bits of the capabilities of the client if they are negotiated, see `Capabilities`
+    pub capabilities: Option<i64>,
+}
+impl_to_params!(RegisterParams<'a> { id => ID, file => FILE } optional { capabilities => CAPABILITIES });
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_params_keys() {
+        let item = CdcDict::from([("id".to_string(), CdcValue::STRING("item".to_string()))]);
+        let params = GetParams { item: item.clone(), name: "name", index: None }.into_params();
+        let mut names: Vec<&str> = params.keys().map(String::as_str).collect();
+        names.sort();
+        assert_eq!(names, ["item", "name"]);
+        let params = GetParams { item, name: "name", index: Some(2) }.to_params();
+        assert_eq!(params[keys::INDEX], CdcValue::INTEGER(2));
+        assert_eq!(params[keys::NAME], CdcValue::STRING("name".to_string()));
+
+        let params = CommandParams { command: "cmd", args: vec![], kwargs: CdcDict::new(), stage: Some(1), selection: None }.into_params();
+        assert_eq!(params[keys::STAGE], CdcValue::INTEGER(1));
+        assert!(!params.contains_key(keys::SELECTION));
+        assert!(!TranslateParams { text: "text", id: None }.to_params().contains_key(keys::ID));
+
+        let mut params = CdcDict::new();
+        insert_optional(&mut params, keys::VALUE, None);
+        assert!(params.is_empty());
+        insert_optional(&mut params, keys::VALUE, Some(CdcValue::NONE));
+        assert_eq!(params[keys::VALUE], CdcValue::NONE);
+    }
+}
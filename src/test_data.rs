@@ -0,0 +1,70 @@
+//! Deterministic sample values for benchmarks and tests.
+//!
+//! The values only depend on their arguments, so measurements of different builds encode the
+//! same bytes.
+
+use crate::encoding::{CdcDict, CdcValue};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+/// The order in which the keys of a map are inserted
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyOrder {
+    Sorted,
+    /// A fixed permutation of the sorted keys
+    Shuffled,
+}
+
+/// Returns a LIST of the integers `0..len`
+pub fn integer_list(len: usize) -> CdcValue {
+    CdcValue::LIST((0..len as i64).map(CdcValue::INTEGER).collect())
+}
+
+/// Returns a MAP of `len` entries with keys like `key00042` and their index as STRING value
+pub fn string_map(len: usize, order: KeyOrder) -> CdcValue {
+    CdcValue::MAP(keys(len, order).map(|(key, value)| (key, CdcValue::STRING(value.to_string()))).collect::<CdcDict>())
+}
+
+/// Returns an ORDERED_MAP like `string_map`, whose key order is kept when encoding
+#[cfg(feature = "ordered-maps")]
+pub fn ordered_string_map(len: usize, order: KeyOrder) -> CdcValue {
+    CdcValue::ORDERED_MAP(keys(len, order).map(|(key, value)| (key, CdcValue::STRING(value.to_string()))).collect())
+}
+
+/// Returns a BLOB of `len` bytes counting up and wrapping around
+pub fn blob(len: usize) -> CdcValue {
+    CdcValue::BLOB((0..len).map(|i| i as u8).collect())
+}
+
+/// Returns the keys of a map with their index in the sorted order
+fn keys(len: usize, order: KeyOrder) -> impl Iterator<Item = (String, usize)> {
+    let mut indices: Vec<usize> = (0..len).collect();
+    if order == KeyOrder::Shuffled {
+        // Fisher-Yates with a fixed xorshift seed, which keeps the permutation stable
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        for i in (1..len).rev() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            indices.swap(i, (state % (i as u64 + 1)) as usize);
+        }
+    }
+    indices.into_iter().map(|i| (format!("key{:05}", i), i))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_test_data() {
+        assert_eq!(integer_list(3), CdcValue::LIST(vec![CdcValue::INTEGER(0), CdcValue::INTEGER(1), CdcValue::INTEGER(2)]));
+        assert_eq!(string_map(100, KeyOrder::Sorted), string_map(100, KeyOrder::Shuffled));
+        let shuffled: Vec<String> = keys(100, KeyOrder::Shuffled).map(|(key, _)| key).collect();
+        assert!(!shuffled.is_sorted());
+        assert_eq!(shuffled, keys(100, KeyOrder::Shuffled).map(|(key, _)| key).collect::<Vec<_>>());
+        assert_eq!(blob(300).expect_blob()[257], 1);
+    }
+}
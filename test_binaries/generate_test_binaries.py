@@ -0,0 +1,70 @@
+"""Writes the CDC fixtures checked in next to this script.
+
+The fixtures of the other `*_matches_python` tests come from `generate_test_binaries.py` of the
+parent repository, which encodes with the `gom` package of ZEISS Inspect. The values here are
+packed with `struct` after the same wire layout instead, so they can be regenerated without an
+installation: a type byte, little-endian 64 bit integers and lengths, and IEEE 754 doubles.
+
+Run with `python test_binaries/generate_test_binaries.py` from the crate root.
+"""
+
+import math
+import struct
+import sys
+from pathlib import Path
+
+LIST, ITEM, INDEXABLE, VEC2D, VEC3D = 5, 8, 9, 17, 18
+
+# Components whose bit patterns must survive decoding, see EDGE_COMPONENTS in src/encoding.rs
+EDGE_COMPONENTS = [
+    -0.0,
+    0.0,
+    math.inf,
+    -math.inf,
+    sys.float_info.min,
+    5e-324,
+    -sys.float_info.max,
+    math.nan,
+]
+
+
+def integer(value):
+    return struct.pack('<q', value)
+
+
+def length(value):
+    return struct.pack('<Q', value)
+
+
+def string(value):
+    data = value.encode('utf-8')
+    return length(len(data)) + data
+
+
+def vector(cdc_type, components):
+    return bytes([cdc_type]) + b''.join(struct.pack('<d', component) for component in components)
+
+
+def cdc_list(values):
+    return bytes([LIST]) + length(len(values)) + b''.join(values)
+
+
+def item(id, category, stage):
+    return bytes([ITEM]) + string(id) + integer(category) + integer(stage)
+
+
+def indexable(item_bytes, token, size):
+    # The item keeps its own type byte
+    return bytes([INDEXABLE]) + item_bytes + string(token) + integer(size)
+
+
+FIXTURES = {
+    'vec2d_edge': cdc_list([vector(VEC2D, pair) for pair in zip(EDGE_COMPONENTS, reversed(EDGE_COMPONENTS))]),
+    'vec3d_edge': cdc_list([vector(VEC3D, EDGE_COMPONENTS[i:i + 3]) for i in range(len(EDGE_COMPONENTS) - 2)]),
+    'indexable': indexable(item('item123', 42, 7), 'test_token', 100),
+}
+
+if __name__ == '__main__':
+    directory = Path(__file__).parent
+    for name, data in FIXTURES.items():
+        (directory / f'{name}_expected.bin').write_bytes(data)